@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::store;
+use crate::Choice;
+use library::FailedTracks;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Queued,
+    Scheduled,
+    Running,
+    Succeeded,
+    Failed,
+    /* Already in the library, so no job was needed */
+    Skipped,
+}
+
+impl State {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, State::Succeeded | State::Failed | State::Skipped)
+    }
+
+    fn is_success(&self) -> bool {
+        matches!(self, State::Succeeded | State::Skipped)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackStatus {
+    pub id: String,
+    pub state: State,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub name: String,
+    pub track_ids: Vec<String>,
+    pub state: State,
+    #[serde(default)]
+    pub force: bool,
+    /* How many times these tracks were requeued after failing */
+    #[serde(default)]
+    pub requeues: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Download {
+    pub id: String,
+    pub created_at: u64,
+    pub state: State,
+    pub choices: Vec<Choice>,
+    pub tracks: Vec<TrackStatus>,
+    pub jobs: Vec<JobStatus>,
+    /* Choices that couldn't be expanded into tracks */
+    #[serde(default)]
+    pub errors: Vec<String>,
+    /* Set once every choice has been expanded into tracks or errors; until
+     * then the download can't have finished, whatever its tracks say */
+    #[serde(default = "expanded_default")]
+    pub expanded: bool,
+}
+
+/* Downloads stored before `expanded` existed were expanded long ago */
+fn expanded_default() -> bool {
+    true
+}
+
+impl Download {
+    fn update_state(&mut self) {
+        let states: Vec<State> = self.tracks.iter().map(|track| track.state).collect();
+        let finished = self.expanded && states.iter().all(|state| state.is_finished());
+        self.state = if finished && !self.errors.is_empty() {
+            State::Failed
+        } else if finished && states.iter().all(|state| state.is_success()) {
+            State::Succeeded
+        } else if finished {
+            State::Failed
+        } else if states.is_empty() {
+            State::Queued
+        } else if states
+            .iter()
+            .any(|state| *state == State::Running || state.is_finished())
+        {
+            State::Running
+        } else if states.contains(&State::Scheduled) {
+            State::Scheduled
+        } else {
+            State::Queued
+        };
+    }
+}
+
+lazy_static! {
+    static ref DOWNLOADS: Mutex<HashMap<String, Download>> = Mutex::new(HashMap::new());
+}
+
+pub fn create(id: String, choices: Vec<Choice>) {
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+
+    let download = Download {
+        id: id.clone(),
+        created_at,
+        state: State::Queued,
+        choices,
+        tracks: vec![],
+        jobs: vec![],
+        errors: vec![],
+        expanded: false,
+    };
+    store::save_download(&download);
+    DOWNLOADS.lock().unwrap().insert(id, download);
+}
+
+/* Load the downloads saved before a restart. Choices that were still
+ * being expanded won't be, so those downloads finish with what they have. */
+pub fn restore() {
+    let mut downloads = DOWNLOADS.lock().unwrap();
+    for mut download in store::load_downloads() {
+        if !download.expanded {
+            download.expanded = true;
+            download.update_state();
+            store::save_download(&download);
+        }
+        downloads.insert(download.id.clone(), download);
+    }
+}
+
+/* Jobs that were started before a restart but no longer exist can't be
+ * followed any further, so their tracks are marked as failed */
+pub fn reconcile(existing_jobs: &[String]) {
+    let lost_jobs: Vec<String> = DOWNLOADS
+        .lock()
+        .unwrap()
+        .values()
+        .flat_map(|download| download.jobs.iter())
+        .filter(|job| matches!(job.state, State::Scheduled | State::Running))
+        .filter(|job| !existing_jobs.contains(&job.name))
+        .map(|job| job.name.clone())
+        .collect();
+
+    for job_name in lost_jobs {
+        set_job_state(&job_name, State::Failed);
+    }
+}
+
+/* Register tracks that are known but not yet handed to a job */
+pub fn add_tracks(download_id: &str, track_ids: &[String]) {
+    insert_tracks(download_id, track_ids, State::Queued);
+}
+
+/* Register tracks that are already in the library */
+pub fn add_skipped(download_id: &str, track_ids: &[String]) {
+    insert_tracks(download_id, track_ids, State::Skipped);
+}
+
+fn insert_tracks(download_id: &str, track_ids: &[String], state: State) {
+    let mut downloads = DOWNLOADS.lock().unwrap();
+    if let Some(download) = downloads.get_mut(download_id) {
+        for track_id in track_ids {
+            if !download.tracks.iter().any(|track| &track.id == track_id) {
+                download.tracks.push(TrackStatus {
+                    id: track_id.clone(),
+                    state,
+                });
+            }
+        }
+        download.update_state();
+        store::save_download(download);
+    }
+}
+
+pub fn add_error(download_id: &str, error: String) {
+    let mut downloads = DOWNLOADS.lock().unwrap();
+    if let Some(download) = downloads.get_mut(download_id) {
+        download.errors.push(error);
+        download.update_state();
+        store::save_download(download);
+    }
+}
+
+/* Every choice of the download has been expanded */
+pub fn set_expanded(download_id: &str) {
+    let mut downloads = DOWNLOADS.lock().unwrap();
+    if let Some(download) = downloads.get_mut(download_id) {
+        download.expanded = true;
+        download.update_state();
+        store::save_download(download);
+    }
+}
+
+pub fn add_job(download_id: &str, job_name: &str, track_ids: Vec<String>, force: bool) {
+    add_tracks(download_id, &track_ids);
+
+    let mut downloads = DOWNLOADS.lock().unwrap();
+    if let Some(download) = downloads.get_mut(download_id) {
+        download.jobs.push(JobStatus {
+            name: job_name.to_string(),
+            track_ids,
+            state: State::Queued,
+            force,
+            requeues: 0,
+        });
+        store::save_download(download);
+    }
+}
+
+/* A job that just finished, and whether that finished its download too */
+pub struct FinishedJob {
+    pub track_ids: Vec<String>,
+    pub download_finished: bool,
+}
+
+/* Record a job transition and carry it over to the job's tracks */
+pub fn set_job_state(job_name: &str, state: State) -> Option<FinishedJob> {
+    let mut downloads = DOWNLOADS.lock().unwrap();
+    for download in downloads.values_mut() {
+        let job = match download.jobs.iter_mut().find(|job| job.name == job_name) {
+            Some(job) => job,
+            None => continue,
+        };
+        if job.state == state || job.state.is_finished() {
+            return None;
+        }
+        job.state = state;
+
+        let track_ids = job.track_ids.clone();
+        for track in download
+            .tracks
+            .iter_mut()
+            .filter(|track| track_ids.contains(&track.id))
+        {
+            track.state = state;
+        }
+        download.update_state();
+        store::save_download(download);
+        return state.is_finished().then(|| FinishedJob {
+            track_ids,
+            download_finished: download.state.is_finished(),
+        });
+    }
+    None
+}
+
+/* Settle a finished job that listed the tracks it didn't download. Tracks
+ * without a match are marked as failed and the rest as succeeded, while the
+ * failed ones move to `new_job` unless they were already requeued
+ * `max_requeues` times. Returns the new job and whether it is forced, if
+ * there is one. */
+pub fn requeue_failed(
+    job_name: &str,
+    failed: &FailedTracks,
+    new_job: &str,
+    max_requeues: u32,
+) -> Option<(String, bool)> {
+    let mut downloads = DOWNLOADS.lock().unwrap();
+    for download in downloads.values_mut() {
+        let job = match download.jobs.iter().find(|job| job.name == job_name) {
+            Some(job) => job.clone(),
+            None => continue,
+        };
+        let requeue = !failed.failed.is_empty() && job.requeues < max_requeues;
+        for track in download
+            .tracks
+            .iter_mut()
+            .filter(|track| job.track_ids.contains(&track.id))
+        {
+            track.state = if failed.no_match.contains(&track.id) {
+                State::Failed
+            } else if !failed.failed.contains(&track.id) {
+                State::Succeeded
+            } else if requeue {
+                State::Queued
+            } else {
+                State::Failed
+            };
+        }
+        if requeue {
+            download.jobs.push(JobStatus {
+                name: new_job.to_string(),
+                track_ids: failed.failed.clone(),
+                state: State::Queued,
+                force: job.force,
+                requeues: job.requeues + 1,
+            });
+        }
+        download.update_state();
+        store::save_download(download);
+        return requeue.then(|| (new_job.to_string(), job.force));
+    }
+    None
+}
+
+pub fn get(download_id: &str) -> Option<Download> {
+    DOWNLOADS.lock().unwrap().get(download_id).cloned()
+}
+
+pub fn list() -> Vec<Download> {
+    let mut downloads: Vec<Download> = DOWNLOADS.lock().unwrap().values().cloned().collect();
+    downloads.sort_by_key(|download| download.created_at);
+    downloads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn download(errors: &[&str], states: &[State], expanded: bool) -> Download {
+        let mut download = Download {
+            id: "download".to_string(),
+            created_at: 0,
+            state: State::Queued,
+            choices: vec![],
+            tracks: states
+                .iter()
+                .enumerate()
+                .map(|(i, state)| TrackStatus {
+                    id: format!("track-{}", i),
+                    state: *state,
+                })
+                .collect(),
+            jobs: vec![],
+            errors: errors.iter().map(|error| error.to_string()).collect(),
+            expanded,
+        };
+        download.update_state();
+        download
+    }
+
+    #[test]
+    fn finishes_only_once_every_choice_is_expanded() {
+        let failed_album = ["album 4LH4d3cOWNNsVw41Gqt2kv: not found"];
+        assert_eq!(download(&failed_album, &[], false).state, State::Queued);
+        assert_eq!(
+            download(&failed_album, &[State::Succeeded, State::Queued], false).state,
+            State::Running
+        );
+        assert_eq!(
+            download(&[], &[State::Succeeded], false).state,
+            State::Running
+        );
+
+        assert_eq!(
+            download(&[], &[State::Succeeded, State::Skipped], true).state,
+            State::Succeeded
+        );
+        assert_eq!(
+            download(&[], &[State::Succeeded, State::Failed], true).state,
+            State::Failed
+        );
+        assert_eq!(
+            download(&failed_album, &[State::Succeeded], true).state,
+            State::Failed
+        );
+        assert_eq!(download(&failed_album, &[], true).state, State::Failed);
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::{env, fs};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use tokio::process::Command;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::{sleep, Duration};
+
+use k8s_openapi::api::batch::v1::{Job, JobSpec};
+
+use k8s_openapi::api::core::v1::{
+    Container, EnvVar, PersistentVolumeClaimVolumeSource, PodTemplateSpec, Volume,
+};
+use kube::{
+    api::{Api, ListParams, ObjectMeta, PostParams},
+    runtime::watcher::{watcher, Event},
+    Client,
+};
+
+use crate::downloads::State;
+use crate::rescan::jobs_notify;
+use crate::scheduler::{Batch, JobEvent};
+use library::{DEFAULT_TEMPLATE, NOTIFIER_ENV};
+
+/* Label put on every job we create, so the scheduler only watches its own jobs */
+const MANAGED_BY_LABEL: &str = "app.kubernetes.io/managed-by";
+const MANAGED_BY_VALUE: &str = "distributed-streaming-api";
+
+/* Starts a downloader for a batch of comma separated track ids as the
+ * batch's job. Executors report job transitions back to the scheduler through the
+ * event channel they were created with; bounding the number of concurrent
+ * jobs is left to the scheduler. */
+#[async_trait]
+pub trait Executor: Send + Sync {
+    /* Names of the jobs started by any instance of the API that still exist */
+    async fn existing_jobs(&self) -> Result<Vec<String>, Box<dyn Error + Send + Sync>>;
+
+    async fn launch(&self, batch: &Batch) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+pub async fn executor_from_env(events: UnboundedSender<JobEvent>) -> Arc<dyn Executor> {
+    match env::var("EXECUTOR")
+        .unwrap_or_else(|_| "kubernetes".to_string())
+        .as_str()
+    {
+        "local" => Arc::new(LocalExecutor::new(events)),
+        _ => Arc::new(KubernetesExecutor::new(events).await),
+    }
+}
+
+/* Environment passed to every downloader, regardless of where it runs */
+fn downloader_env(batch: &Batch, music_home: String) -> Vec<(String, String)> {
+    let mut vars = vec![
+        ("JOB_NAME".to_string(), batch.job_name.clone()),
+        ("TRACK_IDS".to_string(), batch.track_ids.clone()),
+        ("FORCE".to_string(), batch.force.to_string()),
+        (
+            "SPOTIFY_CLIENT_ID".to_string(),
+            env::var("SPOTIFY_CLIENT_ID").unwrap_or_default(),
+        ),
+        (
+            "SPOTIFY_CLIENT_SECRET".to_string(),
+            env::var("SPOTIFY_CLIENT_SECRET").unwrap_or_default(),
+        ),
+        ("MUSIC_HOME".to_string(), music_home),
+        (
+            "PATH_TEMPLATE".to_string(),
+            env::var("PATH_TEMPLATE").unwrap_or_else(|_| DEFAULT_TEMPLATE.to_string()),
+        ),
+        (
+            "OUTPUT_FORMAT".to_string(),
+            env::var("OUTPUT_FORMAT").unwrap_or_else(|_| "mp3".to_string()),
+        ),
+        (
+            "DOWNLOAD_CONCURRENCY".to_string(),
+            env::var("DOWNLOAD_CONCURRENCY").unwrap_or_else(|_| "4".to_string()),
+        ),
+        (
+            "DOWNLOAD_RETRIES".to_string(),
+            env::var("DOWNLOAD_RETRIES").unwrap_or_else(|_| "3".to_string()),
+        ),
+        (
+            "COVER_MAX_SIZE".to_string(),
+            env::var("COVER_MAX_SIZE").unwrap_or_default(),
+        ),
+        (
+            "COVER_FILES".to_string(),
+            env::var("COVER_FILES").unwrap_or_else(|_| "cover,folder".to_string()),
+        ),
+        (
+            "LOUDNESS".to_string(),
+            env::var("LOUDNESS").unwrap_or_else(|_| "replaygain".to_string()),
+        ),
+        (
+            "LYRICS_PROVIDER".to_string(),
+            env::var("LYRICS_PROVIDER").unwrap_or_else(|_| "none".to_string()),
+        ),
+        (
+            "LYRICS_URL".to_string(),
+            env::var("LYRICS_URL").unwrap_or_default(),
+        ),
+    ];
+    /* Otherwise the API refreshes the media servers once downloads finish */
+    if jobs_notify() {
+        vars.extend(
+            NOTIFIER_ENV
+                .iter()
+                .map(|name| (name.to_string(), env::var(name).unwrap_or_default())),
+        );
+    }
+    vars
+}
+
+fn get_kubernetes_namespace() -> Result<String, std::io::Error> {
+    fs::read_to_string("/var/run/secrets/kubernetes.io/serviceaccount/namespace")
+}
+
+fn create_job_spec(batch: &Batch) -> Job {
+    let env = downloader_env(batch, "/music".to_string())
+        .into_iter()
+        .map(|(name, value)| EnvVar {
+            name,
+            value: Some(value),
+            ..Default::default()
+        })
+        .collect();
+
+    Job {
+        metadata: ObjectMeta {
+            name: Some(batch.job_name.clone()),
+            labels: Some(BTreeMap::from([(
+                MANAGED_BY_LABEL.to_string(),
+                MANAGED_BY_VALUE.to_string(),
+            )])),
+            ..Default::default()
+        },
+        spec: Some(JobSpec {
+            template: PodTemplateSpec {
+                metadata: Some(ObjectMeta {
+                    ..Default::default()
+                }),
+                spec: Some(k8s_openapi::api::core::v1::PodSpec {
+                    restart_policy: Some("Never".to_string()),
+                    node_selector: Some(BTreeMap::from([(
+                        "kubernetes.io/arch".to_string(),
+                        "arm64".to_string(),
+                    )])),
+                    containers: vec![Container {
+                        name: "downloader".to_string(),
+                        image: Some(
+                            "docker.prayujt.com/distributed-streaming-downloader".to_string(),
+                        ),
+                        env: Some(env),
+                        volume_mounts: Some(vec![k8s_openapi::api::core::v1::VolumeMount {
+                            name: "music-storage".to_string(),
+                            mount_path: "/music".to_string(),
+                            ..Default::default()
+                        }]),
+                        ..Default::default()
+                    }],
+                    volumes: Some(vec![Volume {
+                        name: "music-storage".to_string(),
+                        persistent_volume_claim: Some(PersistentVolumeClaimVolumeSource {
+                            claim_name: env::var("MUSIC_STORAGE_PVC")
+                                .unwrap_or("music-storage".to_string()),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }]),
+                    ..Default::default()
+                }),
+            },
+            backoff_limit: Some(0),
+            /* Keep finished jobs around long enough for their status to be synced */
+            ttl_seconds_after_finished: Some(300),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+pub struct KubernetesExecutor {
+    jobs: Api<Job>,
+}
+
+impl KubernetesExecutor {
+    pub async fn new(events: UnboundedSender<JobEvent>) -> KubernetesExecutor {
+        let namespace = get_kubernetes_namespace().unwrap_or_else(|_| "default".to_string());
+        let client = Client::try_default()
+            .await
+            .expect("Failed to create K8s client");
+        let jobs: Api<Job> = Api::namespaced(client, &namespace);
+
+        tokio::spawn(watch_jobs(jobs.clone(), events));
+
+        KubernetesExecutor { jobs }
+    }
+}
+
+fn managed_jobs() -> ListParams {
+    ListParams::default().labels(&format!("{}={}", MANAGED_BY_LABEL, MANAGED_BY_VALUE))
+}
+
+/* Forward changes to our jobs to the scheduler for as long as it is listening */
+async fn watch_jobs(jobs: Api<Job>, events: UnboundedSender<JobEvent>) {
+    let mut stream = watcher(jobs, managed_jobs()).boxed();
+
+    while let Some(event) = stream.next().await {
+        let event = match event {
+            Ok(Event::Applied(job)) => match job.metadata.name.clone() {
+                Some(name) => JobEvent::Updated(name, job_state(&job)),
+                None => continue,
+            },
+            Ok(Event::Deleted(job)) => match job.metadata.name {
+                Some(name) => JobEvent::Deleted(name),
+                None => continue,
+            },
+            Ok(Event::Restarted(jobs)) => JobEvent::Restarted(
+                jobs.iter()
+                    .filter_map(|job| job.metadata.name.clone().map(|name| (name, job_state(job))))
+                    .collect(),
+            ),
+            Err(e) => {
+                println!("Failed to watch jobs: {:?}", e);
+                sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+        if events.send(event).is_err() {
+            break;
+        }
+    }
+}
+
+fn job_state(job: &Job) -> State {
+    match &job.status {
+        Some(status) if status.succeeded.unwrap_or(0) > 0 => State::Succeeded,
+        Some(status) if status.failed.unwrap_or(0) > 0 => State::Failed,
+        Some(status) if status.active.unwrap_or(0) > 0 => State::Running,
+        _ => State::Scheduled,
+    }
+}
+
+#[async_trait]
+impl Executor for KubernetesExecutor {
+    async fn existing_jobs(&self) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .jobs
+            .list(&managed_jobs())
+            .await?
+            .items
+            .into_iter()
+            .filter_map(|job| job.metadata.name)
+            .collect())
+    }
+
+    async fn launch(&self, batch: &Batch) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let job = create_job_spec(batch);
+        self.jobs.create(&PostParams::default(), &job).await?;
+        println!("Job created successfully.");
+        Ok(())
+    }
+}
+
+/* Spawns the downloader binary as a child process on this machine */
+pub struct LocalExecutor {
+    binary: String,
+    music_home: String,
+    events: UnboundedSender<JobEvent>,
+}
+
+impl LocalExecutor {
+    pub fn new(events: UnboundedSender<JobEvent>) -> LocalExecutor {
+        LocalExecutor {
+            binary: env::var("DOWNLOADER_BIN").unwrap_or_else(|_| "downloader".to_string()),
+            music_home: env::var("MUSIC_HOME").unwrap_or_else(|_| "/music".to_string()),
+            events,
+        }
+    }
+
+    fn command(&self, batch: &Batch) -> Command {
+        let mut command = Command::new(&self.binary);
+        /* The child is dropped with the task waiting on it when the API
+         * shuts down, so no downloader outlives the API */
+        command.kill_on_drop(true);
+        command.envs(downloader_env(batch, self.music_home.clone()));
+        /* Children inherit the API's environment, which would have every
+         * downloader notify the media servers on top of the API */
+        if !jobs_notify() {
+            for name in NOTIFIER_ENV {
+                command.env_remove(name);
+            }
+        }
+        if let Ok(dir) = env::var("DOWNLOADER_DIR") {
+            command.current_dir(dir);
+        }
+        command
+    }
+}
+
+#[async_trait]
+impl Executor for LocalExecutor {
+    /* Child processes are killed when the API shuts down */
+    async fn existing_jobs(&self) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+        Ok(vec![])
+    }
+
+    async fn launch(&self, batch: &Batch) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut child = self.command(batch).spawn()?;
+        println!("Downloader process started.");
+        let _ = self
+            .events
+            .send(JobEvent::Updated(batch.job_name.clone(), State::Running));
+
+        let job_name = batch.job_name.clone();
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            let state = match child.wait().await {
+                Ok(status) => {
+                    println!("Downloader process exited: {}", status);
+                    if status.success() {
+                        State::Succeeded
+                    } else {
+                        State::Failed
+                    }
+                }
+                Err(e) => {
+                    println!("Failed to wait on downloader process: {:?}", e);
+                    State::Failed
+                }
+            };
+            let _ = events.send(JobEvent::Updated(job_name, state));
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+
+    #[test]
+    fn local_downloaders_leave_notifying_to_the_api() {
+        let (events, _) = unbounded_channel();
+        let executor = LocalExecutor::new(events);
+        let batch = Batch {
+            job_name: "downloader-1".to_string(),
+            track_ids: "4uLU6hMCjMI75M1A2tKUQC".to_string(),
+            force: false,
+        };
+
+        let command = executor.command(&batch);
+        let envs: Vec<(&OsStr, Option<&OsStr>)> = command.as_std().get_envs().collect();
+        assert!(envs.contains(&(
+            OsStr::new("TRACK_IDS"),
+            Some(OsStr::new("4uLU6hMCjMI75M1A2tKUQC"))
+        )));
+        for name in NOTIFIER_ENV {
+            assert!(envs.contains(&(OsStr::new(name), None)), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use library::{sanitize, LibraryIndex, OutputFormat, PathFields, PathTemplate};
+use spotify::Track;
+
+/* Write an extended M3U playlist into `music_home`, with entries relative to
+ * it. Tracks in the library are listed where the index says they are; the
+ * rest don't need to be downloaded yet, since their paths follow from the
+ * same template the downloader uses. */
+pub fn write_playlist(
+    music_home: &Path,
+    template: &PathTemplate,
+    format: OutputFormat,
+    name: &str,
+    tracks: &[Track],
+) -> io::Result<PathBuf> {
+    let path = music_home.join(format!("{}.m3u8", sanitize(name)));
+    let index = LibraryIndex::new(music_home);
+
+    let mut contents = String::from("#EXTM3U\n");
+    for track in tracks {
+        let artists = track
+            .artists
+            .iter()
+            .map(|artist| artist.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let track_path = match index.find(&track.id, track.external_ids.isrc.as_deref()) {
+            Some(entry) => entry.path,
+            None => template.render(&PathFields::from_track(track, format.extension())),
+        };
+        contents.push_str(&format!(
+            "#EXTINF:{},{} - {}\n{}\n",
+            track.duration_ms / 1000,
+            artists,
+            track.name,
+            track_path.display()
+        ));
+    }
+
+    fs::create_dir_all(music_home)?;
+    fs::write(&path, contents)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use library::IndexEntry;
+    use serde_json::json;
+
+    use super::*;
+
+    fn track(id: &str, name: &str) -> Track {
+        serde_json::from_value(json!({
+            "id": id,
+            "name": name,
+            "track_number": 1,
+            "disc_number": 1,
+            "duration_ms": 200000,
+            "album": {
+                "id": "album",
+                "name": "Album",
+                "album_type": "album",
+                "release_date": "2020-01-01",
+                "total_tracks": 2,
+                "images": [],
+                "artists": [{ "id": "artist", "name": "Artist" }]
+            },
+            "artists": [{ "id": "artist", "name": "Artist" }]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn lists_indexed_tracks_where_they_are() {
+        let music_home = env::temp_dir().join(format!("api-m3u-{}", process::id()));
+        let indexed = PathBuf::from("Artist/Album/01 - Intro (2).mp3");
+        fs::create_dir_all(music_home.join("Artist/Album")).unwrap();
+        fs::write(music_home.join(&indexed), b"").unwrap();
+        LibraryIndex::new(&music_home)
+            .insert(&IndexEntry {
+                track_id: "first".to_string(),
+                isrc: None,
+                album_id: None,
+                path: indexed,
+                loudness: None,
+            })
+            .unwrap();
+
+        let path = write_playlist(
+            &music_home,
+            &PathTemplate::parse("{artist}/{album}/{title}.{ext}").unwrap(),
+            OutputFormat::Mp3,
+            "Mix",
+            &[track("first", "Intro"), track("second", "Outro")],
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            "#EXTM3U\n\
+             #EXTINF:200,Artist - Intro\nArtist/Album/01 - Intro (2).mp3\n\
+             #EXTINF:200,Artist - Outro\nArtist/Album/Outro.mp3\n"
+        );
+
+        fs::remove_dir_all(music_home).unwrap();
+    }
+}
@@ -1,28 +1,38 @@
 use std::collections::{HashMap, VecDeque};
-use std::sync::Mutex;
-use std::{cmp, env, fs};
+use std::env;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 use serde::{Deserialize, Serialize};
 use serde_json::from_value;
-use tokio::time::{sleep, Duration};
+use tokio::signal::unix::{signal, SignalKind};
 use urlencoding::encode;
 use uuid::Uuid;
 
-use k8s_openapi::api::batch::v1::{Job, JobSpec};
-
-use k8s_openapi::api::core::v1::{
-    Container, EnvVar, PersistentVolumeClaimVolumeSource, PodTemplateSpec, Volume,
-};
-use kube::{
-    api::{Api, ListParams, ObjectMeta, PostParams},
-    Client,
-};
-
 use lazy_static::lazy_static;
+use warp::http::StatusCode;
 use warp::Filter;
 
-mod spotify_client;
-use crate::spotify_client::{AlbumTrack, ArtistAlbum, Items, SpotifyClient, SpotifySearchResponse};
+mod downloads;
+
+mod executor;
+
+mod m3u;
+
+mod rescan;
+
+mod scheduler;
+use crate::scheduler::Scheduler;
+
+mod store;
+
+mod spotify_url;
+
+use library::{LibraryIndex, OutputFormat, PathTemplate};
+use spotify::{
+    Album, AlbumTrack, Items, Playlist, PlaylistItem, SpotifyClient, SpotifyError,
+    SpotifySearchResponse, Track,
+};
 
 #[derive(Debug, Deserialize)]
 struct SelectQuery {
@@ -33,10 +43,20 @@ struct SelectQuery {
 struct DownloadQuery {
     indices: String,
     session_id: String,
+    /* Download tracks again even if they are already in the library */
+    #[serde(default)]
+    force: bool,
 }
 
-#[derive(Debug, Clone, Serialize)]
-struct Choice {
+#[derive(Debug, Deserialize)]
+struct UrlQuery {
+    url: String,
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Choice {
     r#type: String,
     id: String,
 }
@@ -47,146 +67,107 @@ struct SelectResponse {
     choices: Vec<String>,
 }
 
+#[derive(Serialize)]
+struct DownloadResponse {
+    download_id: String,
+}
+
 lazy_static! {
     static ref SESSION_CHOICES: Mutex<HashMap<String, Vec<Vec<Choice>>>> =
         Mutex::new(HashMap::new());
 }
 
-fn get_kubernetes_namespace() -> Result<String, std::io::Error> {
-    fs::read_to_string("/var/run/secrets/kubernetes.io/serviceaccount/namespace")
-}
-
-fn create_job_spec(track_ids: String) -> Job {
-    let uuid = Uuid::new_v4().to_string().to_lowercase();
-    let job_name = format!("downloader-{}", uuid);
-
-    Job {
-        metadata: ObjectMeta {
-            name: Some(job_name),
-            ..Default::default()
-        },
-        spec: Some(JobSpec {
-            template: PodTemplateSpec {
-                metadata: Some(ObjectMeta {
-                    ..Default::default()
-                }),
-                spec: Some(k8s_openapi::api::core::v1::PodSpec {
-                    restart_policy: Some("Never".to_string()),
-                    node_selector: Some(std::collections::BTreeMap::from([(
-                        "kubernetes.io/arch".to_string(),
-                        "arm64".to_string(),
-                    )])),
-                    containers: vec![Container {
-                        name: "downloader".to_string(),
-                        image: Some(
-                            "docker.prayujt.com/distributed-streaming-downloader".to_string(),
-                        ),
-                        env: Some(vec![
-                            EnvVar {
-                                name: "TRACK_IDS".to_string(),
-                                value: Some(track_ids),
-                                ..Default::default()
-                            },
-                            EnvVar {
-                                name: "SPOTIFY_CLIENT_ID".to_string(),
-                                value: Some(env::var("SPOTIFY_CLIENT_ID").unwrap_or_default()),
-                                ..Default::default()
-                            },
-                            EnvVar {
-                                name: "SPOTIFY_CLIENT_SECRET".to_string(),
-                                value: Some(env::var("SPOTIFY_CLIENT_SECRET").unwrap_or_default()),
-                                ..Default::default()
-                            },
-                            EnvVar {
-                                name: "MUSIC_HOME".to_string(),
-                                value: Some("/music".to_string()),
-                                ..Default::default()
-                            },
-                            EnvVar {
-                                name: "SUBSONIC_URL".to_string(),
-                                value: Some(env::var("SUBSONIC_URL").unwrap_or_default()),
-                                ..Default::default()
-                            },
-                            EnvVar {
-                                name: "SUBSONIC_PORT".to_string(),
-                                value: Some(env::var("SUBSONIC_PORT").unwrap_or_default()),
-                                ..Default::default()
-                            },
-                            EnvVar {
-                                name: "SUBSONIC_USERNAME".to_string(),
-                                value: Some(env::var("SUBSONIC_USERNAME").unwrap_or_default()),
-                                ..Default::default()
-                            },
-                            EnvVar {
-                                name: "SUBSONIC_PASSWORD".to_string(),
-                                value: Some(env::var("SUBSONIC_PASSWORD").unwrap_or_default()),
-                                ..Default::default()
-                            },
-                        ]),
-                        volume_mounts: Some(vec![k8s_openapi::api::core::v1::VolumeMount {
-                            name: "music-storage".to_string(),
-                            mount_path: "/music".to_string(),
-                            ..Default::default()
-                        }]),
-                        ..Default::default()
-                    }],
-                    volumes: Some(vec![Volume {
-                        name: "music-storage".to_string(),
-                        persistent_volume_claim: Some(PersistentVolumeClaimVolumeSource {
-                            claim_name: env::var("MUSIC_STORAGE_PVC")
-                                .unwrap_or("music-storage".to_string()),
-                            ..Default::default()
-                        }),
-                        ..Default::default()
-                    }]),
-                    ..Default::default()
-                }),
-            },
-            backoff_limit: Some(0),
-            ttl_seconds_after_finished: Some(1),
-            ..Default::default()
-        }),
-        ..Default::default()
-    }
-}
-
 #[tokio::main]
 async fn main() {
     dotenv::dotenv().ok();
+
+    if let Err(e) = store::open_from_env() {
+        println!("{}", e);
+        std::process::exit(1);
+    }
+
+    SESSION_CHOICES
+        .lock()
+        .unwrap()
+        .extend(store::load_sessions());
+    downloads::restore();
+
+    let client_id = env::var("SPOTIFY_CLIENT_ID").expect("Expected a client id");
+    let secret = env::var("SPOTIFY_CLIENT_SECRET").expect("Expected a secret");
+    let client = Arc::new(SpotifyClient::new(client_id, secret));
+    let with_client = warp::any().map(move || client.clone());
+
+    let scheduler = Scheduler::start().await;
+    let with_scheduler = warp::any().map(move || scheduler.clone());
+
     let select_route = warp::post()
         .and(warp::path("select"))
         .and(warp::body::json())
+        .and(with_client.clone())
         .and_then(select_music);
+    let download_url_route = warp::post()
+        .and(warp::path!("download" / "url"))
+        .and(warp::body::json())
+        .and(with_client.clone())
+        .and(with_scheduler.clone())
+        .and_then(download_url);
     let download_route = warp::post()
         .and(warp::path("download"))
         .and(warp::body::json())
+        .and(with_client)
+        .and(with_scheduler)
         .and_then(download_music);
-    let routes = select_route.or(download_route);
-
-    warp::serve(routes).run(([0, 0, 0, 0], 8080)).await;
+    let downloads_route = warp::get()
+        .and(warp::path("downloads"))
+        .and(warp::path::end())
+        .and_then(list_downloads);
+    let download_status_route = warp::get()
+        .and(warp::path!("downloads" / String))
+        .and_then(get_download);
+    let routes = select_route
+        .or(download_url_route)
+        .or(download_route)
+        .or(downloads_route)
+        .or(download_status_route);
+
+    let (_, server) =
+        warp::serve(routes).bind_with_graceful_shutdown(([0, 0, 0, 0], 8080), shutdown());
+    server.await;
+    /* Returning drops the runtime along with any local downloaders */
 }
 
-async fn select_music(body: SelectQuery) -> Result<impl warp::Reply, warp::Rejection> {
-    let client_id = env::var("SPOTIFY_CLIENT_ID").expect("Expected a client id");
-    let secret = env::var("SPOTIFY_CLIENT_SECRET").expect("Expected a secret");
-    let client = SpotifyClient::new(client_id, secret);
+/* Resolves on Ctrl-C or SIGTERM, which is how containers are stopped */
+async fn shutdown() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+    println!("Shutting down");
+}
 
+async fn select_music(
+    body: SelectQuery,
+    client: Arc<SpotifyClient>,
+) -> Result<impl warp::Reply, warp::Rejection> {
     let titles = body.titles.split('\n');
 
     let mut results: Vec<SpotifySearchResponse> = vec![];
     for title in titles {
         match client
-            .api_req(&format!(
-                "/search?q={}&type=track,album,artist",
+            .get::<SpotifySearchResponse>(&format!(
+                "/search?q={}&type=track,album,artist,playlist",
                 encode(title.trim())
             ))
             .await
         {
-            Ok(res) => match from_value::<SpotifySearchResponse>(res) {
-                Ok(result) => results.push(result),
-                Err(e) => println!("Failed to parse JSON: {:?}", e),
-            },
-            Err(e) => println!("Error: {:?}", e),
+            Ok(result) => results.push(result),
+            Err(e) => {
+                return Ok(warp::reply::with_status(
+                    warp::reply::json(&e.to_string()),
+                    error_status(&e),
+                ))
+            }
         }
     }
 
@@ -196,10 +177,15 @@ async fn select_music(body: SelectQuery) -> Result<impl warp::Reply, warp::Rejec
         let tracks = result.tracks.unwrap().items;
         let albums = result.albums.unwrap().items;
         let artists = result.artists.unwrap().items;
+        let playlists: Vec<Playlist> = result
+            .playlists
+            .map(|playlists| playlists.items.into_iter().flatten().collect())
+            .unwrap_or_default();
 
         let mut track_count = 10;
         let mut album_count = 5;
         let mut artist_count = 3;
+        let mut playlist_count = 2;
 
         let mut user_choice: Vec<String> = vec![];
 
@@ -211,33 +197,45 @@ async fn select_music(body: SelectQuery) -> Result<impl warp::Reply, warp::Rejec
             track_count += artist_count - artists.len();
             artist_count = artists.len();
         }
+        if playlists.len() < playlist_count {
+            track_count += playlist_count - playlists.len();
+            playlist_count = playlists.len();
+        }
 
         let mut choices: Vec<Choice> = vec![];
-        for i in 0..cmp::min(track_count, tracks.len()) {
+        for track in tracks.iter().take(track_count) {
             user_choice.push(format!(
                 "Track: {} - {} [{}]",
-                tracks[i].name, tracks[i].album.artists[0].name, tracks[i].album.name
+                track.name, track.album.artists[0].name, track.album.name
             ));
             choices.push(Choice {
                 r#type: "track".to_string(),
-                id: tracks[i].id.clone(),
+                id: track.id.clone(),
             });
         }
-        for i in 0..cmp::min(album_count, albums.len()) {
-            user_choice.push(format!(
-                "Album: {} - {}",
-                albums[i].name, albums[i].artists[0].name
-            ));
+        for album in albums.iter().take(album_count) {
+            user_choice.push(format!("Album: {} - {}", album.name, album.artists[0].name));
             choices.push(Choice {
                 r#type: "album".to_string(),
-                id: albums[i].id.clone(),
+                id: album.id.clone(),
             });
         }
-        for i in 0..cmp::min(artist_count, artists.len()) {
-            user_choice.push(format!("Artist: {}", artists[i].name));
+        for artist in artists.iter().take(artist_count) {
+            user_choice.push(format!("Artist: {}", artist.name));
             choices.push(Choice {
                 r#type: "artist".to_string(),
-                id: artists[i].id.clone(),
+                id: artist.id.clone(),
+            });
+        }
+        for playlist in playlists.iter().take(playlist_count) {
+            user_choice.push(format!(
+                "Playlist: {} - {}",
+                playlist.name,
+                playlist.owner.display_name.as_deref().unwrap_or("Unknown")
+            ));
+            choices.push(Choice {
+                r#type: "playlist".to_string(),
+                id: playlist.id.clone(),
             });
         }
         session.push(choices);
@@ -247,28 +245,38 @@ async fn select_music(body: SelectQuery) -> Result<impl warp::Reply, warp::Rejec
 
     match SESSION_CHOICES.lock() {
         Ok(mut guard) => guard.insert(session_id.clone(), session.clone()),
-        Err(_) => return Ok(warp::reply::json(&"Failed to lock mutex".to_string())),
+        Err(_) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&"Failed to lock mutex".to_string()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
     };
+    store::save_session(&session_id, &session);
 
     let response = SelectResponse {
         session_id,
         choices: user_choices,
     };
 
-    Ok(warp::reply::json(&response))
+    Ok(warp::reply::with_status(
+        warp::reply::json(&response),
+        StatusCode::OK,
+    ))
 }
 
-async fn download_music(body: DownloadQuery) -> Result<impl warp::Reply, warp::Rejection> {
+async fn download_music(
+    body: DownloadQuery,
+    client: Arc<SpotifyClient>,
+    scheduler: Scheduler,
+) -> Result<impl warp::Reply, warp::Rejection> {
     let session_id = body.session_id;
-    let indices: Vec<i8> = match body
+    let indices: Vec<i8> = body
         .indices
         .split(',')
         .map(|s| s.trim().parse::<i8>())
-        .collect()
-    {
-        Ok(vec) => vec,
-        Err(_) => vec![],
-    };
+        .collect::<Result<_, _>>()
+        .unwrap_or_default();
 
     let session = {
         let mut mutex_guard = match SESSION_CHOICES.lock() {
@@ -281,199 +289,319 @@ async fn download_music(body: DownloadQuery) -> Result<impl warp::Reply, warp::R
             None => return Ok(warp::reply::json(&"Session not found".to_string())),
         }
     };
+    store::remove_session(&session_id);
 
-    let client_id = env::var("SPOTIFY_CLIENT_ID").expect("Expected a client id");
-    let secret = env::var("SPOTIFY_CLIENT_SECRET").expect("Expected a secret");
-    let client = SpotifyClient::new(client_id, secret);
+    let selected: Vec<Choice> = indices
+        .iter()
+        .zip(session.iter())
+        .filter_map(|(idx, choices)| choices.get(*idx as usize).cloned())
+        .collect();
 
-    tokio::spawn(async move {
-        for (idx, choices) in indices.iter().zip(session.iter()) {
-            let choice = &choices[*idx as usize];
+    let download_id = start_download(selected, body.force, client, scheduler);
+    Ok(warp::reply::json(&DownloadResponse { download_id }))
+}
 
-            match choice.r#type.as_str() {
-                "track" => process_tracks(choice.id.clone()).await,
-                "album" => process_album(choice.id.clone(), &client).await,
-                "artist" => process_artist(choice.id.clone(), &client).await,
-                _ => {
-                    println!("Unknown type: {}", choice.r#type);
-                }
-            }
+async fn download_url(
+    body: UrlQuery,
+    client: Arc<SpotifyClient>,
+    scheduler: Scheduler,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let choice = match spotify_url::parse(&body.url) {
+        Some(choice) => choice,
+        None => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&"Invalid Spotify URL".to_string()),
+                StatusCode::BAD_REQUEST,
+            ))
         }
-    });
-    Ok(warp::reply::json(&true))
-}
+    };
 
-async fn process_tracks(track_ids: String) {
-    /* Spawn new Kubernetes jobs for track downloading */
-    println!("Downloading tracks: {}", track_ids);
-    if env::var("ENVIRONMENT")
-        .unwrap_or_else(|_| "production".to_string())
-        .as_str()
-        .eq("development")
+    /* Make sure the link points at something before queueing it */
+    if let Err(e) = client
+        .api_req(&format!("/{}s/{}", choice.r#type, choice.id))
+        .await
     {
-        return;
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&e.to_string()),
+            error_status(&e),
+        ));
     }
-    let namespace = get_kubernetes_namespace().unwrap_or_else(|_| "default".to_string());
-    let client = Client::try_default()
-        .await
-        .expect("Failed to create K8s client");
-    let jobs: Api<Job> = Api::namespaced(client, &namespace);
 
-    let max_jobs: usize = env::var("NUM_WORKERS")
-        .unwrap_or_else(|_| "8".to_string())
-        .parse()
-        .unwrap_or(8);
+    let download_id = start_download(vec![choice], body.force, client, scheduler);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&DownloadResponse { download_id }),
+        StatusCode::OK,
+    ))
+}
 
-    loop {
-        let current_jobs = jobs
-            .list(&ListParams::default())
-            .await
-            .expect("Failed to list jobs")
-            .items
-            .into_iter()
-            .filter(|job| {
-                job.status
-                    .as_ref()
-                    .map_or(false, |status| status.active.unwrap_or(0) > 0)
-            })
-            .count();
-
-        if current_jobs < max_jobs {
-            let job = create_job_spec(track_ids.clone());
-            match jobs.create(&PostParams::default(), &job).await {
-                Ok(_) => {
-                    println!("Job created successfully.");
-                    break;
+/* Record a new download and process its choices in the background */
+fn start_download(
+    selected: Vec<Choice>,
+    force: bool,
+    client: Arc<SpotifyClient>,
+    scheduler: Scheduler,
+) -> String {
+    let download_id = Uuid::new_v4().to_string();
+    downloads::create(download_id.clone(), selected.clone());
+
+    let id = download_id.clone();
+    tokio::spawn(async move {
+        for choice in selected {
+            let result = match choice.r#type.as_str() {
+                "track" => {
+                    process_batches(&id, vec![choice.id.clone()], force, &scheduler);
+                    Ok(())
                 }
-                Err(e) => {
-                    println!("Failed to create job: {:?}", e);
-                    sleep(Duration::from_secs(10)).await;
+                "album" => process_album(&id, choice.id.clone(), force, &client, &scheduler).await,
+                "artist" => {
+                    process_artist(&id, choice.id.clone(), force, &client, &scheduler).await
                 }
+                "playlist" => {
+                    process_playlist(&id, choice.id.clone(), force, &client, &scheduler).await
+                }
+                _ => {
+                    println!("Unknown type: {}", choice.r#type);
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                println!("Failed to process {} {}: {}", choice.r#type, choice.id, e);
+                downloads::add_error(&id, format!("{} {}: {}", choice.r#type, choice.id, e));
             }
-        } else {
-            println!("Job limit reached. Waiting to retry...");
-            sleep(Duration::from_secs(5)).await;
         }
+        downloads::set_expanded(&id);
+    });
+    download_id
+}
+
+fn error_status(e: &SpotifyError) -> StatusCode {
+    match e {
+        SpotifyError::NotFound(_) => StatusCode::NOT_FOUND,
+        SpotifyError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::BAD_GATEWAY,
     }
 }
 
-async fn process_album(album_id: String, client: &SpotifyClient) {
-    println!("Downloading album: {}", album_id);
+async fn list_downloads() -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&downloads::list()))
+}
 
-    let tracks: Vec<AlbumTrack> = collect_album_tracks(album_id, client).await;
+async fn get_download(download_id: String) -> Result<impl warp::Reply, warp::Rejection> {
+    match downloads::get(&download_id) {
+        Some(download) => Ok(warp::reply::with_status(
+            warp::reply::json(&download),
+            StatusCode::OK,
+        )),
+        None => Ok(warp::reply::with_status(
+            warp::reply::json(&"Download not found".to_string()),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
 
-    let worker_size: usize = env::var("WORKER_SIZE")
-        .unwrap_or_else(|_| "5".to_string())
-        .parse()
-        .unwrap_or(5);
+fn process_tracks(download_id: &str, track_ids: String, force: bool, scheduler: &Scheduler) {
+    /* Queue the tracks for a downloader job */
+    println!("Downloading tracks: {}", track_ids);
+    let job_name = scheduler::job_name();
+    downloads::add_job(
+        download_id,
+        &job_name,
+        track_ids.split(',').map(|id| id.to_string()).collect(),
+        force,
+    );
+
+    scheduler.enqueue(job_name, track_ids, force);
+}
 
-    let mut queue = VecDeque::from(tracks);
-    while !queue.is_empty() {
-        let group = queue
-            .drain(..worker_size.min(queue.len()))
-            .collect::<Vec<_>>();
-        let track_ids = group
-            .iter()
-            .map(|track| track.id.as_str())
-            .collect::<Vec<_>>()
-            .join(",");
-        process_tracks(track_ids).await;
-    }
+async fn process_album(
+    download_id: &str,
+    album_id: String,
+    force: bool,
+    client: &SpotifyClient,
+    scheduler: &Scheduler,
+) -> Result<(), SpotifyError> {
+    println!("Downloading album: {}", album_id);
+
+    let tracks: Vec<AlbumTrack> = collect_album_tracks(album_id, client).await?;
+    process_batches(
+        download_id,
+        tracks.into_iter().map(|track| track.id).collect(),
+        force,
+        scheduler,
+    );
+    Ok(())
 }
 
-async fn process_artist(artist_id: String, client: &SpotifyClient) {
+async fn process_artist(
+    download_id: &str,
+    artist_id: String,
+    force: bool,
+    client: &SpotifyClient,
+    scheduler: &Scheduler,
+) -> Result<(), SpotifyError> {
     println!("Downloading artist: {}", artist_id);
 
-    let albums: Vec<ArtistAlbum> = {
-        let mut albums: Vec<ArtistAlbum> = vec![];
+    let albums: Vec<Album> = {
+        let mut albums: Vec<Album> = vec![];
         let mut offset = 0;
         let limit = 50;
 
         loop {
-            match client
-                .api_req(&format!(
+            let mut result = client
+                .get::<Items<Album>>(&format!(
                     "/artists/{}/albums?offset={}&limit={}",
                     artist_id, offset, limit
                 ))
-                .await
-            {
-                Ok(res) => match from_value::<Items<ArtistAlbum>>(res) {
-                    Ok(mut result) => {
-                        albums.append(&mut result.items);
-                        if result.items.len() < limit {
-                            break;
-                        }
-                        offset += limit;
-                    }
-                    Err(e) => {
-                        println!("Failed to parse JSON: {:?}", e);
-                        break;
-                    }
-                },
-                Err(e) => {
-                    println!("Error: {:?}", e);
-                    break;
-                }
+                .await?;
+            albums.append(&mut result.items);
+            if result.next.is_none() {
+                break;
             }
+            offset += limit;
         }
         albums
     };
 
     let mut all_tracks: Vec<AlbumTrack> = vec![];
     for album in albums {
-        all_tracks.append(&mut collect_album_tracks(album.id, client).await);
+        all_tracks.append(&mut collect_album_tracks(album.id, client).await?);
+    }
+    process_batches(
+        download_id,
+        all_tracks.into_iter().map(|track| track.id).collect(),
+        force,
+        scheduler,
+    );
+    Ok(())
+}
+
+async fn process_playlist(
+    download_id: &str,
+    playlist_id: String,
+    force: bool,
+    client: &SpotifyClient,
+    scheduler: &Scheduler,
+) -> Result<(), SpotifyError> {
+    println!("Downloading playlist: {}", playlist_id);
+
+    let playlist = client
+        .get::<Playlist>(&format!("/playlists/{}?fields=id,name,owner", playlist_id))
+        .await?;
+    let tracks: Vec<Track> = collect_playlist_tracks(playlist_id, client).await?;
+
+    if env::var("WRITE_PLAYLISTS")
+        .unwrap_or_else(|_| "false".to_string())
+        .eq("true")
+    {
+        let music_home = env::var("MUSIC_HOME").unwrap_or_else(|_| "/music".to_string());
+        let result = PathTemplate::from_env()
+            .map_err(|e| e.to_string())
+            .and_then(|template| {
+                let format = OutputFormat::from_env().map_err(|e| e.to_string())?;
+                m3u::write_playlist(
+                    Path::new(&music_home),
+                    &template,
+                    format,
+                    &playlist.name,
+                    &tracks,
+                )
+                .map_err(|e| e.to_string())
+            });
+        match result {
+            Ok(path) => println!("Wrote playlist {}", path.display()),
+            Err(e) => println!("Failed to write playlist {}: {}", playlist.name, e),
+        }
     }
 
+    process_batches(
+        download_id,
+        tracks.into_iter().map(|track| track.id).collect(),
+        force,
+        scheduler,
+    );
+    Ok(())
+}
+
+/* Split the tracks into WORKER_SIZE sized batches, one job per batch.
+ * Tracks already in the library are left out unless forced. */
+fn process_batches(download_id: &str, track_ids: Vec<String>, force: bool, scheduler: &Scheduler) {
+    let music_home = env::var("MUSIC_HOME").unwrap_or_else(|_| "/music".to_string());
+    let index = LibraryIndex::new(Path::new(&music_home));
+    let (skipped, track_ids): (Vec<String>, Vec<String>) = track_ids
+        .into_iter()
+        .partition(|track_id| !force && index.contains(track_id));
+    if !skipped.is_empty() {
+        println!("Skipping {} tracks already in the library", skipped.len());
+    }
+    downloads::add_skipped(download_id, &skipped);
+    downloads::add_tracks(download_id, &track_ids);
+
     let worker_size: usize = env::var("WORKER_SIZE")
-        .unwrap_or_else(|_| "4".to_string())
+        .unwrap_or_else(|_| "5".to_string())
         .parse()
-        .unwrap_or(4);
+        .unwrap_or(5);
 
-    let mut queue = VecDeque::from(all_tracks);
+    let mut queue = VecDeque::from(track_ids);
     while !queue.is_empty() {
         let group = queue
             .drain(..worker_size.min(queue.len()))
             .collect::<Vec<_>>();
-        let track_ids = group
-            .iter()
-            .map(|track| track.id.as_str())
-            .collect::<Vec<_>>()
-            .join(",");
-        process_tracks(track_ids).await;
+        process_tracks(download_id, group.join(","), force, scheduler);
     }
 }
 
-async fn collect_album_tracks(album_id: String, client: &SpotifyClient) -> Vec<AlbumTrack> {
+async fn collect_album_tracks(
+    album_id: String,
+    client: &SpotifyClient,
+) -> Result<Vec<AlbumTrack>, SpotifyError> {
     let mut tracks: Vec<AlbumTrack> = vec![];
     let mut offset = 0;
     let limit = 50;
 
     loop {
-        match client
-            .api_req(&format!(
+        let mut result = client
+            .get::<Items<AlbumTrack>>(&format!(
                 "/albums/{}/tracks?offset={}&limit={}",
                 album_id, offset, limit
             ))
-            .await
-        {
-            Ok(res) => match from_value::<Items<AlbumTrack>>(res) {
-                Ok(mut result) => {
-                    tracks.append(&mut result.items);
-                    if result.items.len() < limit {
-                        break;
-                    }
-                    offset += limit;
-                }
-                Err(e) => {
-                    println!("Failed to parse JSON: {:?}", e);
-                    break;
-                }
-            },
-            Err(e) => {
-                println!("Error: {:?}", e);
-                break;
+            .await?;
+        tracks.append(&mut result.items);
+        if result.next.is_none() {
+            break;
+        }
+        offset += limit;
+    }
+    Ok(tracks)
+}
+
+async fn collect_playlist_tracks(
+    playlist_id: String,
+    client: &SpotifyClient,
+) -> Result<Vec<Track>, SpotifyError> {
+    let mut tracks: Vec<Track> = vec![];
+    let mut offset = 0;
+    let limit = 50;
+
+    loop {
+        let result = client
+            .get::<Items<PlaylistItem>>(&format!(
+                "/playlists/{}/tracks?offset={}&limit={}",
+                playlist_id, offset, limit
+            ))
+            .await?;
+        for item in result.items {
+            if item.is_local {
+                continue;
             }
+            /* Episodes and removed tracks are skipped */
+            if let Some(Ok(track)) = item.track.map(from_value::<Track>) {
+                tracks.push(track);
+            }
+        }
+        if result.next.is_none() {
+            break;
         }
+        offset += limit;
     }
-    tracks
+    Ok(tracks)
 }
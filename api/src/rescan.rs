@@ -0,0 +1,121 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::{timeout, Duration};
+
+use library::{notifiers_from_env, LibraryIndex, Notifier};
+
+enum Message {
+    /* Tracks of a job that finished */
+    Finished(Vec<String>),
+    Flush,
+}
+
+/* Handle to the task that refreshes the media servers after downloads.
+ * Finished jobs are gathered into a single refresh, sent once a whole
+ * download has finished or no job has finished for RESCAN_DELAY seconds.
+ * Refreshes run one at a time, so scans never overlap. */
+#[derive(Clone)]
+pub struct Rescanner {
+    messages: UnboundedSender<Message>,
+}
+
+/* Whether downloaders notify the media servers themselves when their job
+ * is done, rather than leaving it to the API */
+pub fn jobs_notify() -> bool {
+    env::var("RESCAN_BY").is_ok_and(|by| by == "job")
+}
+
+impl Rescanner {
+    /* None when the jobs notify instead, or no media server is configured */
+    pub fn from_env() -> Result<Option<Rescanner>, String> {
+        match env::var("RESCAN_BY").unwrap_or_default().as_str() {
+            "api" | "" => {}
+            "job" => return Ok(None),
+            other => return Err(format!("Unknown RESCAN_BY: {}", other)),
+        }
+        let notifiers = notifiers_from_env()?;
+        if notifiers.is_empty() {
+            return Ok(None);
+        }
+        let delay = match env::var("RESCAN_DELAY") {
+            Ok(delay) if !delay.is_empty() => delay
+                .parse()
+                .map_err(|_| format!("Invalid RESCAN_DELAY: {}", delay))?,
+            _ => 30,
+        };
+        let music_home = env::var("MUSIC_HOME").unwrap_or_else(|_| "/music".to_string());
+
+        let (messages, receiver) = unbounded_channel();
+        tokio::spawn(run(
+            notifiers,
+            LibraryIndex::new(Path::new(&music_home)),
+            Duration::from_secs(delay),
+            receiver,
+        ));
+        Ok(Some(Rescanner { messages }))
+    }
+
+    pub fn job_finished(&self, track_ids: Vec<String>, download_finished: bool) {
+        let _ = self.messages.send(Message::Finished(track_ids));
+        if download_finished {
+            let _ = self.messages.send(Message::Flush);
+        }
+    }
+}
+
+async fn run(
+    notifiers: Vec<Box<dyn Notifier>>,
+    index: LibraryIndex,
+    delay: Duration,
+    mut receiver: UnboundedReceiver<Message>,
+) {
+    let mut track_ids: Vec<String> = vec![];
+    loop {
+        let message = if track_ids.is_empty() {
+            receiver.recv().await
+        } else {
+            timeout(delay, receiver.recv())
+                .await
+                .unwrap_or(Some(Message::Flush))
+        };
+        match message {
+            Some(Message::Finished(finished)) => track_ids.extend(finished),
+            Some(Message::Flush) => {
+                let dirs = changed_dirs(&index, &track_ids);
+                track_ids.clear();
+                /* Nothing to refresh when every track failed */
+                if dirs.is_empty() {
+                    continue;
+                }
+                for notifier in &notifiers {
+                    match notifier.notify(&dirs).await {
+                        Ok(()) => {
+                            println!("Notified {} of {} directories", notifier.name(), dirs.len())
+                        }
+                        Err(e) => println!("Failed to notify {}: {}", notifier.name(), e),
+                    }
+                }
+            }
+            None => break,
+        }
+    }
+}
+
+/* The album directories the downloaded tracks were written to, relative to
+ * MUSIC_HOME */
+fn changed_dirs(index: &LibraryIndex, track_ids: &[String]) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = vec![];
+    for entry in track_ids
+        .iter()
+        .filter_map(|track_id| index.find(track_id, None))
+    {
+        if let Some(dir) = entry.path.parent() {
+            if !dirs.iter().any(|changed| changed == dir) {
+                dirs.push(dir.to_path_buf());
+            }
+        }
+    }
+    dirs
+}
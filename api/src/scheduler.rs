@@ -0,0 +1,415 @@
+use std::collections::{HashSet, VecDeque};
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::{sleep, Duration};
+use uuid::Uuid;
+
+use crate::downloads::{self, State};
+use crate::executor::{executor_from_env, Executor};
+use crate::rescan::Rescanner;
+use crate::store;
+use library::take_failed;
+
+/* A job as reported by an executor */
+#[derive(Debug)]
+pub enum JobEvent {
+    Updated(String, State),
+    Deleted(String),
+    /* Every job that currently exists; any job missing from it is gone */
+    Restarted(Vec<(String, State)>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Batch {
+    pub job_name: String,
+    pub track_ids: String,
+    /* Download the tracks even if they are already in the library */
+    #[serde(default)]
+    pub force: bool,
+}
+
+/* A new, unique name for a downloader job */
+pub fn job_name() -> String {
+    format!("downloader-{}", Uuid::new_v4().to_string().to_lowercase())
+}
+
+enum Message {
+    Enqueue(Batch),
+    Job(JobEvent),
+    Retry,
+}
+
+/* Handle to the scheduler task, which owns the queue of track batches and
+ * launches a job for the next batch whenever fewer than NUM_WORKERS jobs are
+ * active. Queued batches are kept in the store until their job is launched. */
+#[derive(Clone)]
+pub struct Scheduler {
+    messages: UnboundedSender<Message>,
+}
+
+impl Scheduler {
+    pub async fn start() -> Scheduler {
+        let (messages, receiver) = unbounded_channel();
+        let (events, mut event_receiver) = unbounded_channel();
+
+        let executor = executor_from_env(events).await;
+
+        let rescanner = match Rescanner::from_env() {
+            Ok(rescanner) => rescanner,
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        };
+
+        let forward = messages.clone();
+        tokio::spawn(async move {
+            while let Some(event) = event_receiver.recv().await {
+                if forward.send(Message::Job(event)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let max_jobs: usize = env::var("NUM_WORKERS")
+            .unwrap_or_else(|_| "8".to_string())
+            .parse()
+            .unwrap_or(8);
+        /* How often the tracks a job gave up on are requeued */
+        let max_requeues: u32 = env::var("MAX_REQUEUES")
+            .unwrap_or_else(|_| "2".to_string())
+            .parse()
+            .unwrap_or(2);
+
+        /* Resume the batches that were queued before a restart, skipping
+         * the ones whose job was launched just before it */
+        let existing_jobs = loop {
+            match executor.existing_jobs().await {
+                Ok(jobs) => break jobs,
+                Err(e) => {
+                    println!("Failed to list existing jobs: {:?}", e);
+                    sleep(Duration::from_secs(5)).await;
+                }
+            }
+        };
+        let mut queue = VecDeque::new();
+        for batch in store::load_batches() {
+            if existing_jobs.contains(&batch.job_name) {
+                store::remove_batch(&batch.job_name);
+            } else {
+                queue.push_back(batch);
+            }
+        }
+        downloads::reconcile(&existing_jobs);
+        if !queue.is_empty() {
+            println!("Resuming {} queued batches", queue.len());
+        }
+
+        let worker = Worker {
+            executor,
+            rescanner,
+            messages: messages.clone(),
+            queue,
+            active: HashSet::new(),
+            max_jobs,
+            max_requeues,
+            music_home: PathBuf::from(
+                env::var("MUSIC_HOME").unwrap_or_else(|_| "/music".to_string()),
+            ),
+            paused: false,
+        };
+        tokio::spawn(worker.run(receiver));
+
+        Scheduler { messages }
+    }
+
+    pub fn enqueue(&self, job_name: String, track_ids: String, force: bool) {
+        let batch = Batch {
+            job_name,
+            track_ids,
+            force,
+        };
+        store::save_batch(&batch);
+        if self.messages.send(Message::Enqueue(batch)).is_err() {
+            println!("Scheduler is not running");
+        }
+    }
+}
+
+struct Worker {
+    executor: Arc<dyn Executor>,
+    rescanner: Option<Rescanner>,
+    messages: UnboundedSender<Message>,
+    queue: VecDeque<Batch>,
+    active: HashSet<String>,
+    max_jobs: usize,
+    max_requeues: u32,
+    /* Where downloaders list the tracks they gave up on */
+    music_home: PathBuf,
+    /* Set after a failed launch until the retry delay has passed */
+    paused: bool,
+}
+
+impl Worker {
+    async fn run(mut self, mut receiver: UnboundedReceiver<Message>) {
+        while let Some(message) = receiver.recv().await {
+            match message {
+                Message::Enqueue(batch) => self.queue.push_back(batch),
+                Message::Job(event) => self.handle_event(event),
+                Message::Retry => self.paused = false,
+            }
+            self.dispatch().await;
+        }
+    }
+
+    fn handle_event(&mut self, event: JobEvent) {
+        match event {
+            JobEvent::Updated(name, state) => {
+                self.set_job_state(&name, state);
+                if state.is_finished() {
+                    self.active.remove(&name);
+                } else {
+                    self.active.insert(name);
+                }
+            }
+            JobEvent::Deleted(name) => {
+                /* A job removed before finishing never completed its tracks */
+                self.set_job_state(&name, State::Failed);
+                self.active.remove(&name);
+            }
+            JobEvent::Restarted(jobs) => {
+                let active: Vec<String> = self.active.drain().collect();
+                for name in active {
+                    if !jobs.iter().any(|(job_name, _)| *job_name == name) {
+                        self.set_job_state(&name, State::Failed);
+                    }
+                }
+                for (name, state) in jobs {
+                    self.set_job_state(&name, state);
+                    if !state.is_finished() {
+                        self.active.insert(name);
+                    }
+                }
+            }
+        }
+    }
+
+    /* Jobs that finish are handed to the rescanner, whichever way they
+     * ended, since a failed job may still have downloaded some tracks */
+    fn set_job_state(&mut self, name: &str, state: State) {
+        let finished = match downloads::set_job_state(name, state) {
+            Some(finished) => finished,
+            None => return,
+        };
+        let requeued = self.requeue_failed(name);
+        if let Some(rescanner) = &self.rescanner {
+            rescanner.job_finished(finished.track_ids, finished.download_finished && !requeued);
+        }
+    }
+
+    /* A job that gave up on some of its tracks or found no match for them
+     * lists them, and only the ones it gave up on are queued again. Returns
+     * whether they were. */
+    fn requeue_failed(&mut self, name: &str) -> bool {
+        let failed = match take_failed(&self.music_home, name) {
+            Some(failed) => failed,
+            None => return false,
+        };
+        if !failed.no_match.is_empty() {
+            println!("No match for {} tracks of {}", failed.no_match.len(), name);
+        }
+        let (job_name, force) =
+            match downloads::requeue_failed(name, &failed, &job_name(), self.max_requeues) {
+                Some(job) => job,
+                None => {
+                    if !failed.failed.is_empty() {
+                        println!("Giving up on {} tracks of {}", failed.failed.len(), name);
+                    }
+                    return false;
+                }
+            };
+        println!(
+            "Requeueing {} tracks of {} as {}",
+            failed.failed.len(),
+            name,
+            job_name
+        );
+        let batch = Batch {
+            job_name,
+            track_ids: failed.failed.join(","),
+            force,
+        };
+        store::save_batch(&batch);
+        self.queue.push_back(batch);
+        true
+    }
+
+    async fn dispatch(&mut self) {
+        while !self.paused && self.active.len() < self.max_jobs {
+            let batch = match self.queue.pop_front() {
+                Some(batch) => batch,
+                None => break,
+            };
+
+            match self.executor.launch(&batch).await {
+                Ok(_) => {
+                    store::remove_batch(&batch.job_name);
+                    downloads::set_job_state(&batch.job_name, State::Scheduled);
+                    self.active.insert(batch.job_name);
+                }
+                Err(e) => {
+                    println!("Failed to create job: {:?}", e);
+                    self.queue.push_front(batch);
+                    self.paused = true;
+
+                    let messages = self.messages.clone();
+                    tokio::spawn(async move {
+                        sleep(Duration::from_secs(10)).await;
+                        let _ = messages.send(Message::Retry);
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::process;
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use library::{save_failed, FailedTracks};
+
+    use super::*;
+
+    /* Records the jobs it is asked to launch, failing them while `down` */
+    #[derive(Default)]
+    struct FakeExecutor {
+        launched: Mutex<Vec<String>>,
+        down: Mutex<bool>,
+    }
+
+    #[async_trait]
+    impl Executor for FakeExecutor {
+        async fn existing_jobs(&self) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+            Ok(vec![])
+        }
+
+        async fn launch(&self, batch: &Batch) -> Result<(), Box<dyn Error + Send + Sync>> {
+            if *self.down.lock().unwrap() {
+                return Err("executor is down".into());
+            }
+            self.launched.lock().unwrap().push(batch.job_name.clone());
+            Ok(())
+        }
+    }
+
+    fn worker(executor: Arc<FakeExecutor>, max_jobs: usize, batches: &[&str]) -> Worker {
+        store::open_for_tests();
+        let (messages, _) = unbounded_channel();
+        Worker {
+            executor,
+            rescanner: None,
+            messages,
+            queue: batches
+                .iter()
+                .map(|job_name| Batch {
+                    job_name: job_name.to_string(),
+                    track_ids: "4uLU6hMCjMI75M1A2tKUQC".to_string(),
+                    force: false,
+                })
+                .collect(),
+            active: HashSet::new(),
+            max_jobs,
+            max_requeues: 2,
+            music_home: env::temp_dir().join(format!("api-music-{}", process::id())),
+            paused: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn launches_jobs_as_capacity_frees_up() {
+        let executor = Arc::new(FakeExecutor::default());
+        let mut worker = worker(executor.clone(), 2, &["job-1", "job-2", "job-3"]);
+
+        worker.dispatch().await;
+        assert_eq!(*executor.launched.lock().unwrap(), ["job-1", "job-2"]);
+        assert_eq!(worker.queue.len(), 1);
+
+        worker.handle_event(JobEvent::Updated("job-1".to_string(), State::Running));
+        worker.dispatch().await;
+        assert_eq!(worker.queue.len(), 1);
+
+        worker.handle_event(JobEvent::Updated("job-1".to_string(), State::Succeeded));
+        worker.dispatch().await;
+        assert_eq!(
+            *executor.launched.lock().unwrap(),
+            ["job-1", "job-2", "job-3"]
+        );
+        assert!(worker.queue.is_empty());
+        assert_eq!(worker.active.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn keeps_batches_queued_while_launches_fail() {
+        let executor = Arc::new(FakeExecutor::default());
+        *executor.down.lock().unwrap() = true;
+        let mut worker = worker(executor.clone(), 2, &["job-4", "job-5"]);
+
+        worker.dispatch().await;
+        assert!(worker.paused);
+        assert!(executor.launched.lock().unwrap().is_empty());
+        assert_eq!(worker.queue.front().unwrap().job_name, "job-4");
+
+        *executor.down.lock().unwrap() = false;
+        worker.dispatch().await;
+        assert!(executor.launched.lock().unwrap().is_empty());
+
+        worker.paused = false;
+        worker.dispatch().await;
+        assert_eq!(*executor.launched.lock().unwrap(), ["job-4", "job-5"]);
+    }
+
+    #[tokio::test]
+    async fn fails_unmatched_tracks_without_requeueing_them() {
+        let executor = Arc::new(FakeExecutor::default());
+        let mut worker = worker(executor.clone(), 2, &[]);
+        downloads::create("download-1".to_string(), vec![]);
+        downloads::add_job(
+            "download-1",
+            "job-6",
+            vec!["matched".to_string(), "unmatched".to_string()],
+            false,
+        );
+        downloads::set_expanded("download-1");
+        save_failed(
+            &worker.music_home,
+            "job-6",
+            &FailedTracks {
+                failed: vec![],
+                no_match: vec!["unmatched".to_string()],
+            },
+        )
+        .unwrap();
+
+        worker.handle_event(JobEvent::Updated("job-6".to_string(), State::Succeeded));
+        let download = downloads::get("download-1").unwrap();
+        let states: Vec<(&str, State)> = download
+            .tracks
+            .iter()
+            .map(|track| (track.id.as_str(), track.state))
+            .collect();
+        assert_eq!(
+            states,
+            [("matched", State::Succeeded), ("unmatched", State::Failed)]
+        );
+        assert_eq!(download.state, State::Failed);
+        assert!(worker.queue.is_empty());
+    }
+}
@@ -0,0 +1,115 @@
+use crate::Choice;
+
+const TYPES: [&str; 4] = ["track", "album", "artist", "playlist"];
+
+/* Turn an open.spotify.com link or a spotify: URI into a choice, e.g.
+ * https://open.spotify.com/intl-de/album/<id>?si=... or spotify:track:<id> */
+pub fn parse(link: &str) -> Option<Choice> {
+    let link = link.trim();
+
+    let (r#type, id) = if let Some(uri) = link.strip_prefix("spotify:") {
+        let mut parts = uri.split(':');
+        (parts.next()?, parts.next()?)
+    } else {
+        let path = link
+            .strip_prefix("https://")
+            .or_else(|| link.strip_prefix("http://"))
+            .unwrap_or(link)
+            .strip_prefix("open.spotify.com/")?;
+        let path = path.split(['?', '#']).next()?;
+
+        let mut segments = path
+            .split('/')
+            .filter(|segment| !segment.is_empty() && !segment.starts_with("intl-"));
+        (segments.next()?, segments.next()?)
+    };
+
+    let valid_id = !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric());
+    if !TYPES.contains(&r#type) || !valid_id {
+        return None;
+    }
+
+    Some(Choice {
+        r#type: r#type.to_string(),
+        id: id.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Case {
+        link: &'static str,
+        expected: Option<(&'static str, &'static str)>,
+    }
+
+    #[test]
+    fn parses_links_and_uris() {
+        let cases = vec![
+            Case {
+                link: "https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC",
+                expected: Some(("track", "4uLU6hMCjMI75M1A2tKUQC")),
+            },
+            Case {
+                link: "https://open.spotify.com/album/4LH4d3cOWNNsVw41Gqt2kv?si=a1b2c3d4e5f6",
+                expected: Some(("album", "4LH4d3cOWNNsVw41Gqt2kv")),
+            },
+            Case {
+                link: "http://open.spotify.com/artist/0k17h0D3J5VfsdmQ1iZtE9/",
+                expected: Some(("artist", "0k17h0D3J5VfsdmQ1iZtE9")),
+            },
+            Case {
+                link: "open.spotify.com/playlist/37i9dQZF1DXcBWIGoYBM5M#top",
+                expected: Some(("playlist", "37i9dQZF1DXcBWIGoYBM5M")),
+            },
+            Case {
+                link: " https://open.spotify.com/intl-de/album/4LH4d3cOWNNsVw41Gqt2kv?si=x ",
+                expected: Some(("album", "4LH4d3cOWNNsVw41Gqt2kv")),
+            },
+            Case {
+                link: "spotify:track:4uLU6hMCjMI75M1A2tKUQC",
+                expected: Some(("track", "4uLU6hMCjMI75M1A2tKUQC")),
+            },
+            Case {
+                link: "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M",
+                expected: Some(("playlist", "37i9dQZF1DXcBWIGoYBM5M")),
+            },
+            Case {
+                link: "https://example.com/track/4uLU6hMCjMI75M1A2tKUQC",
+                expected: None,
+            },
+            Case {
+                link: "https://open.spotify.com.evil.com/track/4uLU6hMCjMI75M1A2tKUQC",
+                expected: None,
+            },
+            Case {
+                link: "https://open.spotify.com/show/5CfCWKI5pZ28U0uOzXkDHe",
+                expected: None,
+            },
+            Case {
+                link: "spotify:episode:512ojhOuo1ktJprKbVcKyQ",
+                expected: None,
+            },
+            Case {
+                link: "https://open.spotify.com/track/",
+                expected: None,
+            },
+            Case {
+                link: "https://open.spotify.com/track/not-an-id",
+                expected: None,
+            },
+        ];
+        for case in cases {
+            let result = parse(case.link);
+            assert_eq!(
+                result
+                    .as_ref()
+                    .map(|choice| (choice.r#type.as_str(), choice.id.as_str())),
+                case.expected,
+                "{}",
+                case.link
+            );
+        }
+    }
+}
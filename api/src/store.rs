@@ -0,0 +1,135 @@
+use std::env;
+use std::sync::OnceLock;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::downloads::Download;
+use crate::scheduler::Batch;
+use crate::Choice;
+
+/* Everything the API needs to pick up where it left off after a restart */
+struct Store {
+    db: sled::Db,
+    sessions: sled::Tree,
+    downloads: sled::Tree,
+    batches: sled::Tree,
+}
+
+static STORE: OnceLock<Store> = OnceLock::new();
+
+/* Opens the store at STORE_PATH; must succeed before anything is stored */
+pub fn open_from_env() -> Result<(), String> {
+    open(&env::var("STORE_PATH").unwrap_or_else(|_| "/data/distributed-streaming".to_string()))
+}
+
+pub fn open(path: &str) -> Result<(), String> {
+    let db = sled::open(path).map_err(|e| format!("Failed to open store at {}: {}", path, e))?;
+    let tree = |name: &str| {
+        db.open_tree(name)
+            .map_err(|e| format!("Failed to open {} in store at {}: {}", name, path, e))
+    };
+    let store = Store {
+        sessions: tree("sessions")?,
+        downloads: tree("downloads")?,
+        batches: tree("batches")?,
+        db,
+    };
+    STORE
+        .set(store)
+        .map_err(|_| "Store is already open".to_string())
+}
+
+/* A store in the temp directory, shared by every test of the process */
+#[cfg(test)]
+pub fn open_for_tests() {
+    static OPEN: std::sync::Once = std::sync::Once::new();
+    OPEN.call_once(|| {
+        let path = env::temp_dir().join(format!("api-store-{}", std::process::id()));
+        open(&path.to_string_lossy()).unwrap();
+    });
+}
+
+fn store() -> &'static Store {
+    STORE.get().expect("Store used before it was opened")
+}
+
+fn insert<T: Serialize>(tree: &sled::Tree, key: impl AsRef<[u8]>, value: &T) {
+    let result = serde_json::to_vec(value)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| tree.insert(key, bytes).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        println!("Failed to write to store: {}", e);
+    }
+}
+
+fn load<T: DeserializeOwned>(tree: &sled::Tree) -> Vec<(Vec<u8>, T)> {
+    tree.iter()
+        .filter_map(|entry| match entry {
+            Ok((key, value)) => match serde_json::from_slice::<T>(&value) {
+                Ok(value) => Some((key.to_vec(), value)),
+                Err(e) => {
+                    println!("Failed to parse stored value: {:?}", e);
+                    None
+                }
+            },
+            Err(e) => {
+                println!("Failed to read from store: {:?}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+pub fn save_session(session_id: &str, session: &Vec<Vec<Choice>>) {
+    insert(&store().sessions, session_id, session);
+}
+
+pub fn remove_session(session_id: &str) {
+    if let Err(e) = store().sessions.remove(session_id) {
+        println!("Failed to remove session: {:?}", e);
+    }
+}
+
+pub fn load_sessions() -> Vec<(String, Vec<Vec<Choice>>)> {
+    load(&store().sessions)
+        .into_iter()
+        .map(|(key, session)| (String::from_utf8_lossy(&key).to_string(), session))
+        .collect()
+}
+
+pub fn save_download(download: &Download) {
+    insert(&store().downloads, &download.id, download);
+}
+
+pub fn load_downloads() -> Vec<Download> {
+    load(&store().downloads)
+        .into_iter()
+        .map(|(_, download)| download)
+        .collect()
+}
+
+/* Batches are keyed by a monotonically increasing id so they load in queue order */
+pub fn save_batch(batch: &Batch) {
+    match store().db.generate_id() {
+        Ok(id) => insert(&store().batches, id.to_be_bytes(), batch),
+        Err(e) => println!("Failed to write to store: {:?}", e),
+    }
+}
+
+pub fn remove_batch(job_name: &str) {
+    for (key, batch) in load::<Batch>(&store().batches) {
+        if batch.job_name == job_name {
+            if let Err(e) = store().batches.remove(key) {
+                println!("Failed to remove batch: {:?}", e);
+            }
+        }
+    }
+}
+
+pub fn load_batches() -> Vec<Batch> {
+    load(&store().batches)
+        .into_iter()
+        .map(|(_, batch)| batch)
+        .collect()
+}
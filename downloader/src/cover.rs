@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use image::imageops::FilterType;
+use image::ImageFormat;
+use reqwest::Client;
+use tokio::sync::OnceCell;
+
+use library::write_atomic;
+use spotify::{Album, Image};
+
+use crate::tagging::Cover;
+
+/* Next to the library index, so every job shares the cache */
+const CACHE_DIR: &str = ".library/covers";
+
+/* Fetches each album's cover once per job, and only once at all while it is
+ * cached on the music volume. Tracks of the same album downloaded at the
+ * same time wait for the one fetch. */
+pub struct Covers {
+    cache_dir: PathBuf,
+    max_size: Option<u32>,
+    /* Names the cover is saved under in each album directory, without the
+     * extension */
+    file_names: Vec<String>,
+    client: Client,
+    fetched: Mutex<HashMap<String, Arc<OnceCell<Option<Cover>>>>>,
+}
+
+impl Covers {
+    pub fn from_env(music_home: &Path) -> Result<Covers, String> {
+        let max_size = match env::var("COVER_MAX_SIZE") {
+            Ok(size) if !size.is_empty() => Some(
+                size.parse()
+                    .map_err(|_| format!("Invalid COVER_MAX_SIZE: {}", size))?,
+            ),
+            _ => None,
+        };
+        let file_names = env::var("COVER_FILES")
+            .unwrap_or_else(|_| "cover,folder".to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect();
+        Ok(Covers::new(music_home, max_size, file_names))
+    }
+
+    pub fn new(music_home: &Path, max_size: Option<u32>, file_names: Vec<String>) -> Covers {
+        Covers {
+            cache_dir: music_home.join(CACHE_DIR),
+            max_size,
+            file_names,
+            client: Client::new(),
+            fetched: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /* The album's cover, or None when Spotify has none or it can't be
+     * fetched. Failures are remembered so they aren't retried per track. */
+    pub async fn get(&self, album: &Album) -> Option<Cover> {
+        let cell = self
+            .fetched
+            .lock()
+            .unwrap()
+            .entry(album.id.clone())
+            .or_default()
+            .clone();
+        cell.get_or_init(|| async {
+            match self.load(album).await {
+                Ok(cover) => cover,
+                Err(e) => {
+                    println!("Failed to get cover for {}: {}", album.name, e);
+                    None
+                }
+            }
+        })
+        .await
+        .clone()
+    }
+
+    async fn load(&self, album: &Album) -> Result<Option<Cover>, String> {
+        let cache_path = self.cache_path(&album.id);
+        if let Ok(data) = fs::read(&cache_path) {
+            return Ok(to_cover(data));
+        }
+
+        let image = match pick_image(&album.images, self.max_size) {
+            Some(image) => image,
+            None => return Ok(None),
+        };
+        let res = self
+            .client
+            .get(&image.url)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| e.to_string())?;
+        let mut data = res.bytes().await.map_err(|e| e.to_string())?.to_vec();
+        if mime_type(&data).is_none() {
+            return Err("Cover is not a JPEG, PNG or WebP image".to_string());
+        }
+        if let Some(max_size) = self.max_size {
+            data = resize(data, max_size)?;
+        }
+
+        /* Other jobs may read the cache while it's being written */
+        write_atomic(&cache_path, &data).map_err(|e| e.to_string())?;
+        Ok(to_cover(data))
+    }
+
+    /* Covers resized to different sizes are cached separately */
+    fn cache_path(&self, album_id: &str) -> PathBuf {
+        match self.max_size {
+            Some(max_size) => self.cache_dir.join(format!("{}-{}", album_id, max_size)),
+            None => self.cache_dir.join(album_id),
+        }
+    }
+
+    /* Save the cover into the album directory for players that read it
+     * from there, leaving any cover already there alone */
+    pub fn save_to_dir(&self, cover: &Cover, dir: &Path) {
+        let ext = match cover.mime_type.as_str() {
+            "image/png" => "png",
+            "image/webp" => "webp",
+            _ => "jpg",
+        };
+        for name in &self.file_names {
+            let path = dir.join(format!("{}.{}", name, ext));
+            if path.exists() {
+                continue;
+            }
+            if let Err(e) = fs::write(&path, &cover.data) {
+                println!("Failed to write {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn to_cover(data: Vec<u8>) -> Option<Cover> {
+    let mime_type = mime_type(&data)?;
+    Some(Cover {
+        mime_type: mime_type.to_string(),
+        data,
+    })
+}
+
+/* The smallest image that is still at least `max_size` on its longest side,
+ * or the largest one when none is. Spotify lists sizes largest first, but
+ * doesn't always say what they are. */
+pub fn pick_image(images: &[Image], max_size: Option<u32>) -> Option<&Image> {
+    let size = |image: &Image| image.width.max(image.height).unwrap_or(0);
+    let largest = images.iter().max_by_key(|image| size(image));
+    match max_size {
+        Some(max_size) => images
+            .iter()
+            .filter(|image| size(image) >= max_size)
+            .min_by_key(|image| size(image))
+            .or(largest),
+        None => largest,
+    }
+}
+
+/* Detected from the data, since image URLs have no extension */
+pub fn mime_type(data: &[u8]) -> Option<&'static str> {
+    match image::guess_format(data).ok()? {
+        ImageFormat::Jpeg => Some("image/jpeg"),
+        ImageFormat::Png => Some("image/png"),
+        ImageFormat::WebP => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/* Scale the image down so neither side exceeds `max_size`, keeping its
+ * format. Smaller images are left as they are. */
+pub fn resize(data: Vec<u8>, max_size: u32) -> Result<Vec<u8>, String> {
+    let format = image::guess_format(&data).map_err(|e| e.to_string())?;
+    let image = image::load_from_memory_with_format(&data, format).map_err(|e| e.to_string())?;
+    if image.width() <= max_size && image.height() <= max_size {
+        return Ok(data);
+    }
+
+    let resized = image.resize(max_size, max_size, FilterType::Lanczos3);
+    let mut output = Cursor::new(vec![]);
+    resized
+        .write_to(&mut output, format)
+        .map_err(|e| e.to_string())?;
+    Ok(output.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImageView};
+    use serde_json::json;
+
+    use super::*;
+
+    fn image(url: &str, size: Option<u32>) -> Image {
+        Image {
+            url: url.to_string(),
+            width: size,
+            height: size,
+        }
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut output = Cursor::new(vec![]);
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut output, ImageFormat::Png)
+            .unwrap();
+        output.into_inner()
+    }
+
+    #[test]
+    fn picks_smallest_image_that_is_large_enough() {
+        let images = vec![
+            image("large", Some(640)),
+            image("medium", Some(300)),
+            image("small", Some(64)),
+        ];
+        assert_eq!(pick_image(&images, None).unwrap().url, "large");
+        assert_eq!(pick_image(&images, Some(250)).unwrap().url, "medium");
+        assert_eq!(pick_image(&images, Some(1000)).unwrap().url, "large");
+        assert!(pick_image(&[], None).is_none());
+    }
+
+    #[test]
+    fn detects_mime_type_and_resizes() {
+        let data = png(800, 400);
+        assert_eq!(mime_type(&data), Some("image/png"));
+        assert_eq!(
+            mime_type(b"\xff\xd8\xff\xe0\x00\x10JFIF"),
+            Some("image/jpeg")
+        );
+        assert_eq!(mime_type(b"<html>"), None);
+
+        let resized = resize(data.clone(), 200).unwrap();
+        assert_eq!(mime_type(&resized), Some("image/png"));
+        assert_eq!(
+            image::load_from_memory(&resized).unwrap().dimensions(),
+            (200, 100)
+        );
+        assert_eq!(resize(data.clone(), 1000).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn fetches_each_cover_once() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/cover")
+            .with_body(png(640, 640))
+            .expect(1)
+            .create_async()
+            .await;
+
+        let dir = env::temp_dir().join(format!("downloader-covers-{}", std::process::id()));
+        let album: Album = serde_json::from_value(json!({
+            "id": "4LH4d3cOWNNsVw41Gqt2kv",
+            "name": "The Dark Side of the Moon",
+            "release_date": "1973-03-01",
+            "artists": [],
+            "images": [{ "url": format!("{}/cover", server.url()), "width": 640, "height": 640 }]
+        }))
+        .unwrap();
+
+        let file_names = vec!["cover".to_string(), "folder".to_string()];
+        let covers = Covers::new(&dir, Some(300), file_names.clone());
+        let (cover, again) = tokio::join!(covers.get(&album), covers.get(&album));
+        let cover = cover.unwrap();
+        assert_eq!(cover.mime_type, "image/png");
+        assert_eq!(again, Some(cover.clone()));
+
+        /* A later job reads it from the cache on the volume */
+        let covers = Covers::new(&dir, Some(300), file_names);
+        assert_eq!(covers.get(&album).await, Some(cover.clone()));
+        mock.assert_async().await;
+
+        covers.save_to_dir(&cover, &dir);
+        assert_eq!(fs::read(dir.join("cover.png")).unwrap(), cover.data);
+        assert!(dir.join("folder.png").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
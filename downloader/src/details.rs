@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+
+use spotify::{Album, AlbumTrack, Albums, Artists, Items, SpotifyClient, SpotifyError, Track};
+
+/* Spotify caps how many albums and artists one request can fetch */
+const ALBUMS_PER_REQUEST: usize = 20;
+const ARTISTS_PER_REQUEST: usize = 50;
+
+/* What the full album object adds to the one embedded in a track */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AlbumDetails {
+    pub total_discs: Option<u32>,
+    pub genres: Vec<String>,
+    pub label: Option<String>,
+    pub copyright: Option<String>,
+}
+
+/* Album details and artist genres for a batch of tracks, fetched once so
+ * tracks from the same album or artist don't repeat requests */
+#[derive(Debug, Default)]
+pub struct Details {
+    albums: HashMap<String, AlbumDetails>,
+    artist_genres: HashMap<String, Vec<String>>,
+}
+
+impl Details {
+    pub async fn fetch(client: &SpotifyClient, tracks: &[Track]) -> Result<Details, SpotifyError> {
+        let mut details = Details::default();
+
+        let album_ids = unique(tracks.iter().map(|track| track.album.id.as_str()));
+        for ids in album_ids.chunks(ALBUMS_PER_REQUEST) {
+            let albums = client
+                .get::<Albums>(&format!("/albums?ids={}", ids.join(",")))
+                .await?;
+            for album in albums.albums.into_iter().flatten() {
+                let total_discs = total_discs(client, &album).await?;
+                details
+                    .albums
+                    .insert(album.id.clone(), album_details(album, total_discs));
+            }
+        }
+
+        let artist_ids = unique(
+            tracks
+                .iter()
+                .filter_map(|track| track.artists.first())
+                .map(|artist| artist.id.as_str()),
+        );
+        for ids in artist_ids.chunks(ARTISTS_PER_REQUEST) {
+            let artists = client
+                .get::<Artists>(&format!("/artists?ids={}", ids.join(",")))
+                .await?;
+            for artist in artists.artists.into_iter().flatten() {
+                details.artist_genres.insert(artist.id, artist.genres);
+            }
+        }
+
+        Ok(details)
+    }
+
+    pub fn album(&self, track: &Track) -> Option<&AlbumDetails> {
+        self.albums.get(&track.album.id)
+    }
+
+    /* Spotify rarely sets album genres, so fall back to the genres of the
+     * track's main artist */
+    pub fn genres(&self, track: &Track) -> Vec<String> {
+        match self.album(track) {
+            Some(album) if !album.genres.is_empty() => album.genres.clone(),
+            _ => track
+                .artists
+                .first()
+                .and_then(|artist| self.artist_genres.get(&artist.id))
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+fn unique<'a>(ids: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let mut unique: Vec<&str> = vec![];
+    for id in ids {
+        if !unique.contains(&id) {
+            unique.push(id);
+        }
+    }
+    unique
+}
+
+fn album_details(album: Album, total_discs: Option<u32>) -> AlbumDetails {
+    /* Prefer the copyright over the sound recording copyright */
+    let copyright = album
+        .copyrights
+        .iter()
+        .find(|copyright| copyright.copyright_type == "C")
+        .or(album.copyrights.first())
+        .map(|copyright| copyright.text.clone());
+    AlbumDetails {
+        total_discs,
+        genres: album.genres,
+        label: album.label.filter(|label| !label.is_empty()),
+        copyright,
+    }
+}
+
+/* Tracks are listed in disc order, so the last one is on the last disc. The
+ * album object only embeds the first page of tracks, so longer albums need
+ * one more request for it. */
+async fn total_discs(client: &SpotifyClient, album: &Album) -> Result<Option<u32>, SpotifyError> {
+    let tracks = match &album.tracks {
+        Some(tracks) => tracks,
+        None => return Ok(None),
+    };
+    if tracks.next.is_none() || tracks.total == 0 {
+        return Ok(tracks.items.iter().map(|track| track.disc_number).max());
+    }
+
+    let last = client
+        .get::<Items<AlbumTrack>>(&format!(
+            "/albums/{}/tracks?offset={}&limit=1",
+            album.id,
+            tracks.total - 1
+        ))
+        .await?;
+    Ok(last.items.first().map(|track| track.disc_number))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::tagging::Metadata;
+
+    fn album(genres: &[&str], label: &str) -> Album {
+        serde_json::from_value(json!({
+            "id": "4LH4d3cOWNNsVw41Gqt2kv",
+            "name": "The Dark Side of the Moon",
+            "album_type": "album",
+            "release_date": "1973-03-01",
+            "total_tracks": 2,
+            "artists": [{ "id": "0k17h0D3J5VfsdmQ1iZtE9", "name": "Pink Floyd" }],
+            "genres": genres,
+            "label": label,
+            "copyrights": [
+                { "text": "(P) 2011 Pink Floyd Music Ltd.", "type": "P" },
+                { "text": "(C) 2011 Pink Floyd Music Ltd.", "type": "C" }
+            ],
+            "tracks": {
+                "items": [
+                    {
+                        "id": "6rqhFgbbKwnb9MLmUQDhG6",
+                        "name": "Speak to Me",
+                        "track_number": 1,
+                        "disc_number": 1,
+                        "duration_ms": 67000,
+                        "artists": []
+                    },
+                    {
+                        "id": "7dpBQ0DZKMyJjbXnTanJmF",
+                        "name": "Breathe",
+                        "track_number": 1,
+                        "disc_number": 2,
+                        "duration_ms": 169000,
+                        "artists": []
+                    }
+                ],
+                "next": null,
+                "total": 2
+            }
+        }))
+        .unwrap()
+    }
+
+    fn track(album: &Album) -> Track {
+        let mut album: Value = serde_json::to_value(album).unwrap();
+        album.as_object_mut().unwrap().remove("tracks");
+        serde_json::from_value(json!({
+            "id": "6rqhFgbbKwnb9MLmUQDhG6",
+            "name": "Speak to Me",
+            "track_number": 1,
+            "disc_number": 1,
+            "duration_ms": 67000,
+            "album": album,
+            "artists": [{ "id": "0k17h0D3J5VfsdmQ1iZtE9", "name": "Pink Floyd" }]
+        }))
+        .unwrap()
+    }
+
+    async fn details(album: Album) -> Details {
+        /* Albums with a single page of tracks need no further requests */
+        let client = SpotifyClient::new(String::new(), String::new());
+        let total_discs = total_discs(&client, &album).await.unwrap();
+        Details {
+            albums: HashMap::from([(album.id.clone(), album_details(album, total_discs))]),
+            artist_genres: HashMap::from([(
+                "0k17h0D3J5VfsdmQ1iZtE9".to_string(),
+                vec!["art rock".to_string(), "progressive rock".to_string()],
+            )]),
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_artist_genres() {
+        let album = album(&[], "Pink Floyd Records");
+        let track = track(&album);
+        let details = details(album).await;
+
+        let metadata = Metadata::from_track(&track, &details, None, None, None);
+        assert_eq!(metadata.genres, ["art rock", "progressive rock"]);
+        assert_eq!(metadata.label.as_deref(), Some("Pink Floyd Records"));
+        assert_eq!(
+            metadata.copyright.as_deref(),
+            Some("(C) 2011 Pink Floyd Music Ltd.")
+        );
+        assert_eq!(metadata.total_discs, Some(2));
+    }
+
+    #[tokio::test]
+    async fn prefers_album_genres_and_drops_empty_labels() {
+        let album = album(&["psychedelic rock"], "");
+        let track = track(&album);
+        let details = details(album).await;
+
+        let metadata = Metadata::from_track(&track, &details, None, None, None);
+        assert_eq!(metadata.genres, ["psychedelic rock"]);
+        assert_eq!(metadata.label, None);
+    }
+}
@@ -0,0 +1,321 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use tokio::process::Command;
+
+use library::{LibraryIndex, Loudness, OutputFormat};
+
+use crate::tagging::writer_for;
+
+/* ReplayGain 2.0 plays tracks back at -18 LUFS */
+const REFERENCE_LUFS: f64 = -18.0;
+
+/* What Opus gain tags are relative to */
+const R128_REFERENCE_LUFS: f64 = -23.0;
+
+/* Normalized tracks keep this much headroom below full scale, in dBTP */
+const MAX_TRUE_PEAK: f64 = -1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoudnessMode {
+    Off,
+    /* Leave the audio alone and tell players how much to adjust it */
+    ReplayGain,
+    /* Re-encode every track at the reference loudness */
+    Normalize,
+}
+
+impl LoudnessMode {
+    pub fn from_env() -> Result<LoudnessMode, String> {
+        match env::var("LOUDNESS")
+            .unwrap_or_else(|_| "replaygain".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "off" => Ok(LoudnessMode::Off),
+            "replaygain" | "" => Ok(LoudnessMode::ReplayGain),
+            "normalize" => Ok(LoudnessMode::Normalize),
+            other => Err(format!("Unknown loudness mode: {}", other)),
+        }
+    }
+}
+
+/* How much to adjust playback by in dB, and the peak that is adjusted */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gain {
+    pub gain: f64,
+    pub peak: f64,
+}
+
+impl Gain {
+    pub fn track(loudness: &Loudness) -> Gain {
+        Gain {
+            gain: REFERENCE_LUFS - loudness.integrated,
+            peak: loudness.peak,
+        }
+    }
+
+    /* Tracks are weighted by their length, approximating the loudness of
+     * the album played as one piece */
+    pub fn album(tracks: &[Loudness]) -> Option<Gain> {
+        let duration: f64 = tracks.iter().map(|track| track.duration).sum();
+        if tracks.is_empty() || duration <= 0.0 {
+            return None;
+        }
+        let energy: f64 = tracks
+            .iter()
+            .map(|track| track.duration * 10f64.powf(track.integrated / 10.0))
+            .sum();
+        Some(Gain {
+            gain: REFERENCE_LUFS - 10.0 * (energy / duration).log10(),
+            peak: tracks.iter().map(|track| track.peak).fold(0.0, f64::max),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplayGain {
+    pub track: Gain,
+    /* Only known once the whole album is in the library */
+    pub album: Option<Gain>,
+}
+
+impl ReplayGain {
+    /* REPLAYGAIN_* tags, as ID3, FLAC and MP4 players read them */
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            (
+                "REPLAYGAIN_TRACK_GAIN",
+                format!("{:.2} dB", self.track.gain),
+            ),
+            ("REPLAYGAIN_TRACK_PEAK", format!("{:.6}", self.track.peak)),
+        ];
+        if let Some(album) = self.album {
+            fields.push(("REPLAYGAIN_ALBUM_GAIN", format!("{:.2} dB", album.gain)));
+            fields.push(("REPLAYGAIN_ALBUM_PEAK", format!("{:.6}", album.peak)));
+        }
+        fields
+    }
+
+    /* Opus replaces them with R128_* tags, relative to -23 LUFS and in
+     * 1/256 dB steps */
+    pub fn r128_fields(&self) -> Vec<(&'static str, String)> {
+        let r128 = |gain: &Gain| {
+            (((gain.gain + R128_REFERENCE_LUFS - REFERENCE_LUFS) * 256.0).round() as i32)
+                .to_string()
+        };
+        let mut fields = vec![("R128_TRACK_GAIN", r128(&self.track))];
+        if let Some(album) = &self.album {
+            fields.push(("R128_ALBUM_GAIN", r128(album)));
+        }
+        fields
+    }
+}
+
+/* Measure the file with ffmpeg's EBU R128 filter */
+pub async fn analyze(path: &Path, duration: f64) -> Result<Loudness, String> {
+    let result = Command::new("ffmpeg")
+        .args(["-hide_banner", "-nostats", "-i"])
+        .arg(path)
+        .args(["-filter_complex", "ebur128=peak=true", "-f", "null", "-"])
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run ffmpeg: {}", e))?;
+    if !result.status.success() {
+        return Err(String::from_utf8_lossy(&result.stderr).trim().to_string());
+    }
+
+    let (integrated, peak) = parse_summary(&String::from_utf8_lossy(&result.stderr))
+        .ok_or_else(|| "ffmpeg printed no loudness summary".to_string())?;
+    Ok(Loudness {
+        integrated,
+        peak: 10f64.powf(peak / 20.0),
+        duration,
+    })
+}
+
+/* The integrated loudness and true peak in dBFS from the summary the filter
+ * prints once it is done:
+ *
+ *   Integrated loudness:
+ *     I:         -14.3 LUFS
+ *   ...
+ *   True peak:
+ *     Peak:        0.5 dBFS */
+pub fn parse_summary(output: &str) -> Option<(f64, f64)> {
+    let summary = &output[output.rfind("Summary:")?..];
+    let value = |label: &str| {
+        summary
+            .lines()
+            .map(str::trim)
+            .find_map(|line| line.strip_prefix(label))?
+            .split_whitespace()
+            .next()?
+            .parse::<f64>()
+            .ok()
+    };
+    Some((value("I:")?, value("Peak:")?))
+}
+
+/* Re-encode the file in place so it plays at the reference loudness,
+ * turning it down further if it would otherwise clip */
+pub async fn normalize(
+    path: &Path,
+    format: OutputFormat,
+    loudness: &Loudness,
+) -> Result<(), String> {
+    let peak = 20.0 * loudness.peak.log10();
+    let gain = (REFERENCE_LUFS - loudness.integrated).min(MAX_TRUE_PEAK - peak);
+
+    let temp = path.with_extension(format!("normalized.{}", format.extension()));
+    let result = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+        .arg(path)
+        .args(["-map", "0:a", "-af", &format!("volume={:.2}dB", gain)])
+        .args(encoder_args(format))
+        .arg(&temp)
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run ffmpeg: {}", e))?;
+    if !result.status.success() {
+        let _ = fs::remove_file(&temp);
+        return Err(String::from_utf8_lossy(&result.stderr).trim().to_string());
+    }
+    fs::rename(&temp, path).map_err(|e| e.to_string())
+}
+
+fn encoder_args(format: OutputFormat) -> Vec<&'static str> {
+    match format {
+        OutputFormat::Opus => vec!["-c:a", "libopus", "-b:a", "160k"],
+        OutputFormat::M4a => vec!["-c:a", "aac", "-b:a", "256k"],
+        OutputFormat::Mp3 => vec!["-c:a", "libmp3lame", "-q:a", "0"],
+        OutputFormat::Flac => vec!["-c:a", "flac"],
+    }
+}
+
+/* Write album gain into every track of the album once all of them are in
+ * the library and were measured, whichever job downloaded them */
+pub fn tag_album(index: &LibraryIndex, album_id: &str, total_tracks: u32) -> Result<(), String> {
+    let entries = index.album(album_id);
+    if entries.len() < total_tracks as usize {
+        println!(
+            "Album {} has {} of {} tracks, leaving album gain for later",
+            album_id,
+            entries.len(),
+            total_tracks
+        );
+        return Ok(());
+    }
+    let tracks: Vec<Loudness> = entries.iter().filter_map(|entry| entry.loudness).collect();
+    if tracks.len() < entries.len() {
+        return Err(format!(
+            "Not every track of album {} was measured",
+            album_id
+        ));
+    }
+    let album = Gain::album(&tracks);
+
+    for (entry, loudness) in entries.iter().zip(&tracks) {
+        let path = index.full_path(entry);
+        let format: OutputFormat = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| ext.parse().ok())
+            .ok_or_else(|| format!("Unknown format of {}", path.display()))?;
+        let gain = ReplayGain {
+            track: Gain::track(loudness),
+            album,
+        };
+        writer_for(format)
+            .write_replay_gain(&path, &gain)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    println!(
+        "Wrote album gain for {} tracks of {}",
+        entries.len(),
+        album_id
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loudness(integrated: f64, peak: f64, duration: f64) -> Loudness {
+        Loudness {
+            integrated,
+            peak,
+            duration,
+        }
+    }
+
+    #[test]
+    fn parses_ebur128_summary() {
+        let output = "\
+[Parsed_ebur128_0 @ 0x5581] t: 2.9 TARGET:-23 LUFS M: -14.1 S:-120.7 I: -14.2 LUFS LRA: 0.0 LU FTPK: -1.2 dBFS TPK: -1.0 dBFS
+[Parsed_ebur128_0 @ 0x5581] Summary:
+
+  Integrated loudness:
+    I:         -14.3 LUFS
+    Threshold: -24.6 LUFS
+
+  Loudness range:
+    LRA:         5.2 LU
+    Threshold: -34.4 LUFS
+    LRA low:   -18.9 LUFS
+    LRA high:  -13.7 LUFS
+
+  True peak:
+    Peak:        0.5 dBFS
+";
+        assert_eq!(parse_summary(output), Some((-14.3, 0.5)));
+        assert_eq!(parse_summary("Invalid data found"), None);
+    }
+
+    #[test]
+    fn computes_track_and_album_gain() {
+        let loud = loudness(-8.0, 1.1, 100.0);
+        let quiet = loudness(-18.0, 0.5, 100.0);
+        assert_eq!(Gain::track(&loud).gain, -10.0);
+        assert_eq!(Gain::track(&quiet).gain, 0.0);
+
+        /* The louder track dominates an album played through */
+        let album = Gain::album(&[loud, quiet]).unwrap();
+        assert!((album.gain - -7.40).abs() < 0.01, "{}", album.gain);
+        assert_eq!(album.peak, 1.1);
+        assert_eq!(Gain::album(&[]), None);
+    }
+
+    #[test]
+    fn formats_replay_gain_fields() {
+        let gain = ReplayGain {
+            track: Gain {
+                gain: -6.5,
+                peak: 0.98765432,
+            },
+            album: Some(Gain {
+                gain: -7.0,
+                peak: 1.0,
+            }),
+        };
+        assert_eq!(
+            gain.fields(),
+            vec![
+                ("REPLAYGAIN_TRACK_GAIN", "-6.50 dB".to_string()),
+                ("REPLAYGAIN_TRACK_PEAK", "0.987654".to_string()),
+                ("REPLAYGAIN_ALBUM_GAIN", "-7.00 dB".to_string()),
+                ("REPLAYGAIN_ALBUM_PEAK", "1.000000".to_string()),
+            ]
+        );
+        /* -6.5 dB to -18 LUFS is -11.5 dB to -23 LUFS */
+        assert_eq!(
+            gain.r128_fields(),
+            vec![
+                ("R128_TRACK_GAIN", "-2944".to_string()),
+                ("R128_ALBUM_GAIN", "-3072".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,332 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+
+use spotify::Track;
+
+/* LRCLIB asks clients to identify themselves */
+const USER_AGENT: &str = "distributed-streaming (https://github.com/prayujt/distributed-streaming)";
+
+/* A line of synced lyrics and when it starts, in milliseconds */
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncedLine {
+    pub time: u32,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lyrics {
+    pub plain: String,
+    /* Empty when only unsynced lyrics are known */
+    pub synced: Vec<SyncedLine>,
+}
+
+impl Lyrics {
+    /* Plain lyrics are taken from the synced ones when missing */
+    pub fn new(plain: Option<String>, synced: Option<String>) -> Option<Lyrics> {
+        let synced = synced.map(|lrc| parse_lrc(&lrc)).unwrap_or_default();
+        let plain = plain
+            .filter(|plain| !plain.trim().is_empty())
+            .unwrap_or_else(|| {
+                synced
+                    .iter()
+                    .map(|line| line.text.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            });
+        if plain.trim().is_empty() {
+            return None;
+        }
+        Some(Lyrics { plain, synced })
+    }
+
+    /* The synced lyrics in LRC format, or the plain lyrics when there are
+     * none. Formats without a synced lyrics frame embed this, which players
+     * that understand LRC show in sync. */
+    pub fn lrc(&self) -> String {
+        if self.synced.is_empty() {
+            return self.plain.clone();
+        }
+        self.synced
+            .iter()
+            .map(|line| {
+                format!(
+                    "[{:02}:{:02}.{:02}] {}",
+                    line.time / 60_000,
+                    line.time / 1000 % 60,
+                    line.time / 10 % 100,
+                    line.text
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /* Write the lyrics next to the audio file, where players look for them */
+    pub fn save_sidecar(&self, audio_path: &Path) -> io::Result<PathBuf> {
+        let path = audio_path.with_extension("lrc");
+        fs::write(&path, format!("{}\n", self.lrc()))?;
+        Ok(path)
+    }
+}
+
+/* Lines such as "[01:02.34] text", where a line repeated in the song can
+ * carry several timestamps. Tags like "[ar: Artist]" are left out. */
+pub fn parse_lrc(lrc: &str) -> Vec<SyncedLine> {
+    let mut lines = vec![];
+    for line in lrc.lines() {
+        let mut rest = line.trim();
+        let mut times = vec![];
+        while let Some(end) = rest.strip_prefix('[').and_then(|tag| tag.find(']')) {
+            match parse_timestamp(&rest[1..end + 1]) {
+                Some(time) => times.push(time),
+                None => break,
+            }
+            rest = &rest[end + 2..];
+        }
+        let text = rest.trim();
+        lines.extend(times.into_iter().map(|time| SyncedLine {
+            time,
+            text: text.to_string(),
+        }));
+    }
+    lines.sort_by_key(|line| line.time);
+    lines
+}
+
+fn parse_timestamp(timestamp: &str) -> Option<u32> {
+    let (minutes, seconds) = timestamp.split_once(':')?;
+    let minutes: u32 = minutes.parse().ok()?;
+    let seconds: f64 = seconds.parse().ok()?;
+    if !(0.0..60.0).contains(&seconds) {
+        return None;
+    }
+    Some(minutes * 60_000 + (seconds * 1000.0).round() as u32)
+}
+
+#[derive(Debug)]
+pub enum LyricsError {
+    Request(reqwest::Error),
+    Status(StatusCode),
+}
+
+impl fmt::Display for LyricsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LyricsError::Request(e) => write!(f, "Lyrics request failed: {}", e),
+            LyricsError::Status(status) => write!(f, "Unexpected lyrics response: {}", status),
+        }
+    }
+}
+
+impl std::error::Error for LyricsError {}
+
+impl From<reqwest::Error> for LyricsError {
+    fn from(e: reqwest::Error) -> Self {
+        LyricsError::Request(e)
+    }
+}
+
+#[async_trait]
+pub trait LyricsProvider: Send + Sync {
+    /* None when the provider doesn't know the track or it is instrumental */
+    async fn lyrics(&self, track: &Track) -> Result<Option<Lyrics>, LyricsError>;
+}
+
+/* The provider named by LYRICS_PROVIDER, or None when lyrics are off */
+pub fn provider_from_env() -> Result<Option<Box<dyn LyricsProvider>>, String> {
+    match env::var("LYRICS_PROVIDER")
+        .unwrap_or_else(|_| "none".to_string())
+        .to_lowercase()
+        .as_str()
+    {
+        "none" | "" => Ok(None),
+        "lrclib" => Ok(Some(Box::new(LrcLib::new(
+            env::var("LYRICS_URL")
+                .ok()
+                .filter(|url| !url.is_empty())
+                .unwrap_or_else(|| "https://lrclib.net".to_string()),
+        )))),
+        other => Err(format!("Unknown lyrics provider: {}", other)),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LrcLibLyrics {
+    #[serde(default)]
+    instrumental: bool,
+    plain_lyrics: Option<String>,
+    synced_lyrics: Option<String>,
+}
+
+/* LRCLIB, or any service with the same API */
+pub struct LrcLib {
+    base_url: String,
+    client: Client,
+}
+
+impl LrcLib {
+    pub fn new(base_url: String) -> LrcLib {
+        LrcLib {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client: Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl LyricsProvider for LrcLib {
+    async fn lyrics(&self, track: &Track) -> Result<Option<Lyrics>, LyricsError> {
+        let artist = track
+            .artists
+            .first()
+            .map(|artist| artist.name.as_str())
+            .unwrap_or_default();
+        let duration = (track.duration_ms / 1000).to_string();
+        let res = self
+            .client
+            .get(format!("{}/api/get", self.base_url))
+            .header("User-Agent", USER_AGENT)
+            .query(&[
+                ("track_name", track.name.as_str()),
+                ("artist_name", artist),
+                ("album_name", track.album.name.as_str()),
+                ("duration", duration.as_str()),
+            ])
+            .send()
+            .await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !res.status().is_success() {
+            return Err(LyricsError::Status(res.status()));
+        }
+
+        let found = res.json::<LrcLibLyrics>().await?;
+        if found.instrumental {
+            return Ok(None);
+        }
+        Ok(Lyrics::new(found.plain_lyrics, found.synced_lyrics))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn track() -> Track {
+        serde_json::from_value(json!({
+            "id": "6rqhFgbbKwnb9MLmUQDhG6",
+            "name": "Speak to Me",
+            "track_number": 1,
+            "disc_number": 1,
+            "duration_ms": 67000,
+            "album": {
+                "id": "4LH4d3cOWNNsVw41Gqt2kv",
+                "name": "The Dark Side of the Moon",
+                "album_type": "album",
+                "release_date": "1973-03-01",
+                "total_tracks": 10,
+                "images": [],
+                "artists": [{ "id": "0k17h0D3J5VfsdmQ1iZtE9", "name": "Pink Floyd" }]
+            },
+            "artists": [{ "id": "0k17h0D3J5VfsdmQ1iZtE9", "name": "Pink Floyd" }]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_lrc() {
+        let lines =
+            parse_lrc("[ar: Someone]\n[00:12.50] First\n[00:05.00][01:02.345]Chorus\nnot a line\n");
+        assert_eq!(
+            lines,
+            vec![
+                SyncedLine {
+                    time: 5000,
+                    text: "Chorus".to_string()
+                },
+                SyncedLine {
+                    time: 12500,
+                    text: "First".to_string()
+                },
+                SyncedLine {
+                    time: 62345,
+                    text: "Chorus".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_lrc_and_falls_back_to_plain() {
+        let lyrics = Lyrics::new(None, Some("[00:05.00] One\n[01:02.34] Two".to_string())).unwrap();
+        assert_eq!(lyrics.plain, "One\nTwo");
+        assert_eq!(lyrics.lrc(), "[00:05.00] One\n[01:02.34] Two");
+
+        let plain = Lyrics::new(Some("Just words".to_string()), None).unwrap();
+        assert_eq!(plain.lrc(), "Just words");
+        assert_eq!(Lyrics::new(Some(" ".to_string()), None), None);
+    }
+
+    #[tokio::test]
+    async fn fetches_from_lrclib() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/api/get")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("track_name".into(), "Speak to Me".into()),
+                mockito::Matcher::UrlEncoded("artist_name".into(), "Pink Floyd".into()),
+                mockito::Matcher::UrlEncoded("duration".into(), "67".into()),
+            ]))
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "instrumental": false,
+                    "plainLyrics": "Heartbeat",
+                    "syncedLyrics": "[00:30.00] Heartbeat"
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let lyrics = LrcLib::new(server.url()).lyrics(&track()).await.unwrap();
+
+        mock.assert_async().await;
+        let lyrics = lyrics.unwrap();
+        assert_eq!(lyrics.plain, "Heartbeat");
+        assert_eq!(lyrics.synced[0].time, 30000);
+    }
+
+    #[tokio::test]
+    async fn skips_missing_and_instrumental_tracks() {
+        let mut server = mockito::Server::new_async().await;
+        let missing = server
+            .mock("GET", "/api/get")
+            .match_query(mockito::Matcher::Any)
+            .with_status(404)
+            .create_async()
+            .await;
+        let provider = LrcLib::new(server.url());
+        assert_eq!(provider.lyrics(&track()).await.unwrap(), None);
+        missing.remove_async().await;
+
+        server
+            .mock("GET", "/api/get")
+            .match_query(mockito::Matcher::Any)
+            .with_body(json!({ "instrumental": true, "plainLyrics": null }).to_string())
+            .create_async()
+            .await;
+        assert_eq!(provider.lyrics(&track()).await.unwrap(), None);
+    }
+}
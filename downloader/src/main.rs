@@ -1,39 +1,261 @@
 use std::env;
-use std::process::Command;
+use std::path::PathBuf;
 
-use dotenv;
+use futures::stream::{self, StreamExt};
+use serde::Serialize;
 
-use serde_json::from_value;
-use urlencoding::encode;
+use library::{notifiers_from_env, save_failed, FailedTracks, IndexEntry, LibraryIndex};
+use spotify::{SpotifyClient, Track, Tracks};
 
-mod spotify_client;
-use crate::spotify_client::{SpotifyClient, Tracks};
+mod cover;
+use crate::cover::Covers;
+
+mod details;
+use crate::details::Details;
+
+mod loudness;
+use crate::loudness::LoudnessMode;
+
+mod lyrics;
+use crate::lyrics::{Lyrics, LyricsProvider};
+
+mod matching;
+use crate::matching::{Match, MatchMethod};
+
+mod migrate;
+
+mod retry;
+use crate::retry::Retry;
+
+mod tagging;
+use crate::tagging::Cover;
+
+mod yt_music;
+use crate::yt_music::{YtMusicClient, YtMusicError};
 
 mod yt_download;
-use crate::yt_download::download_track;
-
-fn search_yt_music(
-    track_name: &String,
-    album_name: &String,
-    artist_name: &String,
-) -> Result<String, std::io::Error> {
-    let output = Command::new("python3")
-        .arg("scripts/yt-music.py")
-        .arg(track_name)
-        .arg(album_name)
-        .arg(artist_name)
-        .output()
-        .expect("Failed to execute command");
-
-    if output.status.success() {
-        let output_str = String::from_utf8_lossy(&output.stdout);
-        Ok(output_str.to_string())
-    } else {
-        let error_message = String::from_utf8_lossy(&output.stderr);
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            error_message.to_string(),
-        ))
+use crate::yt_download::{
+    download_track, existing_download, index_entry, DownloadError, Downloaded, Output,
+};
+
+/* Exit status of a job that gave up on some of its tracks after retrying
+ * them, set apart from 1 for a job that couldn't run at all */
+const FAILED_TRACKS_EXIT: i32 = 2;
+
+/* What happened to each track, printed as JSON once the job is done */
+#[derive(Serialize)]
+struct TrackResult {
+    id: String,
+    status: TrackStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    video_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    method: Option<MatchMethod>,
+}
+
+#[derive(Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum TrackStatus {
+    Downloaded,
+    /* Already in the library */
+    Skipped,
+    NoMatch,
+    Failed,
+}
+
+/* Candidates for the track, best first. The ISRC search finds the exact
+ * recording, so searching by name is left for when that turns up nothing
+ * or the recording can't be downloaded. */
+async fn search_yt_music(
+    yt_music: &YtMusicClient,
+    track: &Track,
+    threshold: f64,
+    by_name: bool,
+) -> Result<Vec<Match>, YtMusicError> {
+    if !by_name {
+        if let Some(isrc) = &track.external_ids.isrc {
+            let candidates = yt_music.search(isrc).await?;
+            return Ok(matching::isrc_match(track, &candidates, threshold)
+                .into_iter()
+                .collect());
+        }
+    }
+
+    let query = format!(
+        "{} {} {}",
+        track.name, track.album.name, track.album.artists[0].name
+    );
+    let candidates = yt_music.search(&query).await?;
+
+    Ok(matching::ranked_matches(track, &candidates, threshold))
+}
+
+/* What every track of the job is downloaded with */
+struct Job<'a> {
+    output: &'a Output,
+    details: &'a Details,
+    index: LibraryIndex,
+    yt_music: &'a YtMusicClient,
+    covers: &'a Covers,
+    lyrics_provider: Option<&'a dyn LyricsProvider>,
+    threshold: f64,
+    retry: Retry,
+    force: bool,
+}
+
+impl Job<'_> {
+    /* Find and download the track, returning its index entry if it was
+     * downloaded */
+    async fn process(&self, track: &Track) -> (TrackResult, Option<IndexEntry>) {
+        println!(
+            "Searching for: {} - {} - {}",
+            track.album.artists[0].name, track.album.name, track.name
+        );
+
+        let mut result = TrackResult {
+            id: track.id.clone(),
+            status: TrackStatus::Failed,
+            video_id: None,
+            score: None,
+            method: None,
+        };
+        if !self.force {
+            if let Some(path) = existing_download(track, self.output, &self.index) {
+                println!("Skipping {}, already at {}", track.name, path.display());
+                result.status = TrackStatus::Skipped;
+                return (result, None);
+            }
+        }
+
+        /* Tracks without an ISRC can only be searched for by name */
+        let searches: &[bool] = match track.external_ids.isrc {
+            Some(_) => &[false, true],
+            None => &[true],
+        };
+        let mut tried: Vec<String> = vec![];
+        let mut extras = None;
+        for &by_name in searches {
+            let matches = match self
+                .retry
+                .run(
+                    &format!("Searching for {}", track.name),
+                    YtMusicError::is_transient,
+                    || search_yt_music(self.yt_music, track, self.threshold, by_name),
+                )
+                .await
+            {
+                Ok(matches) => matches,
+                Err(e) => {
+                    println!("Error retrieving yt_music url: {}", e);
+                    return (result, None);
+                }
+            };
+            if !by_name && matches.is_empty() {
+                println!("No ISRC match for {}, searching by name", track.name);
+            }
+
+            for found in matches {
+                /* The name search turns up the ISRC match again */
+                if tried.contains(&found.candidate.video_id) {
+                    continue;
+                }
+                println!(
+                    "Matched {} to {} by {:?} with score {:.2}",
+                    track.name, found.candidate.video_id, found.method, found.score
+                );
+                tried.push(found.candidate.video_id.clone());
+                result.video_id = Some(found.candidate.video_id.clone());
+                result.score = Some(found.score);
+                result.method = Some(found.method);
+
+                if extras.is_none() {
+                    extras = Some(self.extras(track).await);
+                }
+                let (lyrics, cover) = extras.as_ref().unwrap();
+                match self
+                    .retry
+                    .run(
+                        &format!("Downloading {}", track.name),
+                        |e| matches!(e, DownloadError::Failed(_)),
+                        || {
+                            download_track(
+                                track,
+                                found.candidate.url(),
+                                self.output,
+                                self.details,
+                                cover.as_ref(),
+                                lyrics.clone(),
+                            )
+                        },
+                    )
+                    .await
+                {
+                    Ok(downloaded) => {
+                        result.status = TrackStatus::Downloaded;
+                        let entry = self.finish(track, cover.as_ref(), downloaded);
+                        return (result, Some(entry));
+                    }
+                    Err(DownloadError::Unavailable(e)) => println!(
+                        "{} is unavailable, trying the next candidate: {}",
+                        found.candidate.video_id, e
+                    ),
+                    Err(e) => {
+                        println!("Failed to download {}: {}", track.name, e);
+                        return (result, None);
+                    }
+                }
+            }
+        }
+
+        if tried.is_empty() {
+            println!(
+                "No match for {} scored above {:.2}, skipping",
+                track.name, self.threshold
+            );
+            result.status = TrackStatus::NoMatch;
+        } else {
+            println!("None of the matches for {} can be downloaded", track.name);
+        }
+        (result, None)
+    }
+
+    async fn extras(&self, track: &Track) -> (Option<Lyrics>, Option<Cover>) {
+        let lyrics = match self.lyrics_provider {
+            Some(provider) => match provider.lyrics(track).await {
+                Ok(lyrics) => lyrics,
+                Err(e) => {
+                    println!("Failed to fetch lyrics for {}: {}", track.name, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        (lyrics, self.covers.get(&track.album).await)
+    }
+
+    /* Save the cover next to the downloaded track and add it to the index */
+    fn finish(&self, track: &Track, cover: Option<&Cover>, downloaded: Downloaded) -> IndexEntry {
+        if let (Some(cover), Some(dir)) = (cover, downloaded.path.parent()) {
+            self.covers.save_to_dir(cover, dir);
+        }
+        let entry = index_entry(track, self.output, &downloaded.path, downloaded.loudness);
+        if let Err(e) = self.index.insert(&entry) {
+            println!("Failed to index {}: {}", downloaded.path.display(), e);
+        }
+        entry
+    }
+}
+
+/* How many tracks of the job are downloaded at the same time */
+fn concurrency() -> Result<usize, String> {
+    match env::var("DOWNLOAD_CONCURRENCY") {
+        Ok(concurrency) if !concurrency.is_empty() => match concurrency.parse() {
+            Ok(concurrency) if concurrency > 0 => Ok(concurrency),
+            _ => Err(format!("Invalid DOWNLOAD_CONCURRENCY: {}", concurrency)),
+        },
+        _ => Ok(4),
     }
 }
 
@@ -41,71 +263,207 @@ fn search_yt_music(
 async fn main() {
     dotenv::dotenv().ok();
 
+    let output = match Output::from_env() {
+        Ok(output) => output,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    /* `downloader migrate [--dry-run]` renames files from before path
+     * templates instead of downloading anything */
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("migrate") {
+        let dry_run = args.iter().any(|arg| arg == "--dry-run");
+        match migrate::migrate(&output.music_home, &output.template, dry_run) {
+            Ok(summary) => println!(
+                "Renamed {} files, skipped {} without tags",
+                summary.renamed, summary.skipped
+            ),
+            Err(e) => {
+                println!("Failed to migrate: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let concurrency = match concurrency() {
+        Ok(concurrency) => concurrency,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let retry = match Retry::from_env() {
+        Ok(retry) => retry,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let threshold = match matching::threshold_from_env() {
+        Ok(threshold) => threshold,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let covers = match Covers::from_env(&output.music_home) {
+        Ok(covers) => covers,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let lyrics_provider = match lyrics::provider_from_env() {
+        Ok(provider) => provider,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let notifiers = match notifiers_from_env() {
+        Ok(notifiers) => notifiers,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
     let client_id = env::var("SPOTIFY_CLIENT_ID").expect("Expected a client id");
     let secret = env::var("SPOTIFY_CLIENT_SECRET").expect("Expected a secret");
     let client = SpotifyClient::new(client_id, secret);
 
+    let yt_music = YtMusicClient::new(
+        env::var("YT_MUSIC_URL").unwrap_or_else(|_| "https://music.youtube.com".to_string()),
+    );
+
     let track_ids = env::var("TRACK_IDS").expect("Expected track ids");
 
-    let tracks = match client.api_req(&format!("/tracks?ids={}", track_ids)).await {
-        Ok(res) => match from_value::<Tracks>(res) {
-            Ok(result) => result,
-            Err(e) => {
-                println!("Error: {:?}", e);
-                Tracks { tracks: vec![] }
-            }
-        },
+    /* Exit with a failure so the job reports that nothing was downloaded */
+    let tracks = match client
+        .get::<Tracks>(&format!("/tracks?ids={}", track_ids))
+        .await
+    {
+        Ok(result) => result,
         Err(e) => {
-            println!("Error: {:?}", e);
-            Tracks { tracks: vec![] }
+            println!("Failed to fetch tracks: {}", e);
+            std::process::exit(1);
         }
     };
 
-    for track in tracks.tracks {
-        let track_name = &track.name;
-        let album_name = &track.album.name;
-        let artist_name = &track.album.artists[0].name;
+    /* Tracks still get their basic tags if the extra details can't be fetched */
+    let details = match Details::fetch(&client, &tracks.tracks).await {
+        Ok(details) => details,
+        Err(e) => {
+            println!("Failed to fetch album and artist details: {}", e);
+            Details::default()
+        }
+    };
 
-        println!(
-            "Searching for: {} - {} - {}",
-            artist_name, album_name, track_name
-        );
+    /* FORCE=true downloads tracks again even if they are in the library */
+    let force = env::var("FORCE")
+        .map(|force| force == "true")
+        .unwrap_or(false);
 
-        match search_yt_music(&track_name, &album_name, &artist_name) {
-            Ok(url) => download_track(&track, url),
-            Err(e) => {
-                println!("Error retrieving yt_music url: {}", e)
+    let job = Job {
+        output: &output,
+        details: &details,
+        index: LibraryIndex::new(&output.music_home),
+        yt_music: &yt_music,
+        covers: &covers,
+        lyrics_provider: lyrics_provider.as_deref(),
+        threshold,
+        retry,
+        force,
+    };
+    let job = &job;
+    let mut processed: Vec<(usize, TrackResult, Option<IndexEntry>)> =
+        stream::iter(tracks.tracks.iter().enumerate())
+            .map(|(position, track)| async move {
+                let (result, entry) = job.process(track).await;
+                (position, result, entry)
+            })
+            .buffer_unordered(concurrency)
+            .collect()
+            .await;
+    processed.sort_by_key(|(position, _, _)| *position);
+
+    let mut results = vec![];
+    /* Albums with a newly downloaded track, which may now be complete */
+    let mut albums: Vec<(String, u32)> = vec![];
+    /* Directories with new files, relative to MUSIC_HOME, for the media
+     * servers to refresh */
+    let mut changed: Vec<PathBuf> = vec![];
+    for ((_, result, entry), track) in processed.into_iter().zip(&tracks.tracks) {
+        if let Some(entry) = entry {
+            if !albums.iter().any(|(id, _)| *id == track.album.id) {
+                albums.push((track.album.id.clone(), track.album.total_tracks));
             }
-        };
+            if let Some(dir) = entry.path.parent() {
+                if !changed.iter().any(|changed| changed == dir) {
+                    changed.push(dir.to_path_buf());
+                }
+            }
+        }
+        results.push(result);
     }
 
-    let subsonic_url = env::var("SUBSONIC_URL").expect("Expected a subsonic url");
-    let subsonic_port = env::var("SUBSONIC_PORT").expect("Expected a subsonic port");
-    let subsonic_username = env::var("SUBSONIC_USERNAME").expect("Expected a subsonic username");
-    let subsonic_password = env::var("SUBSONIC_PASSWORD").expect("Expected a subsonic password");
-
-    let encoded_username = encode(&subsonic_username);
-    let encoded_password = encode(&subsonic_password);
+    if output.loudness == LoudnessMode::ReplayGain {
+        for (album_id, total_tracks) in albums {
+            if let Err(e) = loudness::tag_album(&job.index, &album_id, total_tracks) {
+                println!("Failed to write album gain: {}", e);
+            }
+        }
+    }
 
-    let curl_url = format!(
-        "{}:{}/rest/startScan?u={}&p={}&v=1.15.0&c=CLI",
-        subsonic_url, subsonic_port, encoded_username, encoded_password
-    );
+    match serde_json::to_string(&results) {
+        Ok(results) => println!("Results: {}", results),
+        Err(e) => println!("Failed to serialize results: {}", e),
+    }
+    let with_status = |status: TrackStatus| -> Vec<String> {
+        results
+            .iter()
+            .filter(|result| result.status == status)
+            .map(|result| result.id.clone())
+            .collect()
+    };
+    let failed = FailedTracks {
+        failed: with_status(TrackStatus::Failed),
+        no_match: with_status(TrackStatus::NoMatch),
+    };
 
-    let curl_output = Command::new("curl")
-        .arg(&curl_url)
-        .output()
-        .expect("Failed to execute curl command");
+    /* Nothing to refresh when every track was skipped or failed */
+    if !changed.is_empty() {
+        for notifier in &notifiers {
+            match notifier.notify(&changed).await {
+                Ok(()) => println!(
+                    "Notified {} of {} directories",
+                    notifier.name(),
+                    changed.len()
+                ),
+                Err(e) => println!("Failed to notify {}: {}", notifier.name(), e),
+            }
+        }
+    }
 
-    if curl_output.status.success() {
-        println!(
-            "Curl request successful: {}",
-            String::from_utf8_lossy(&curl_output.stdout)
-        );
-    } else {
-        println!(
-            "Error in curl request: {}",
-            String::from_utf8_lossy(&curl_output.stderr)
-        );
+    /* The API reads the lists once the job has ended, requeues just the
+     * failed tracks and reports the unmatched ones */
+    if !failed.is_empty() {
+        if let Ok(job_name) = env::var("JOB_NAME") {
+            if let Err(e) = save_failed(&output.music_home, &job_name, &failed) {
+                println!("Failed to save failed tracks: {}", e);
+            }
+        }
+    }
+    if !failed.failed.is_empty() {
+        std::process::exit(FAILED_TRACKS_EXIT);
     }
 }
@@ -0,0 +1,570 @@
+use std::env;
+
+use serde::Serialize;
+use strsim::normalized_levenshtein;
+
+use spotify::Track;
+
+use crate::yt_music::Candidate;
+
+const TITLE_WEIGHT: f64 = 0.45;
+const ARTIST_WEIGHT: f64 = 0.30;
+const ALBUM_WEIGHT: f64 = 0.10;
+const DURATION_WEIGHT: f64 = 0.15;
+
+/* Words that mark a different recording of the same song. A candidate whose
+ * title has one of these when the Spotify title doesn't (or the other way
+ * around) is very likely the wrong version. */
+const VERSION_KEYWORDS: [&str; 11] = [
+    "live",
+    "remix",
+    "cover",
+    "acoustic",
+    "instrumental",
+    "karaoke",
+    "demo",
+    "sped up",
+    "slowed",
+    "nightcore",
+    "reverb",
+];
+const VERSION_MISMATCH_PENALTY: f64 = 0.5;
+
+/* Durations within this many seconds count as identical, and differences
+ * beyond the upper bound score nothing */
+const DURATION_TOLERANCE_SECS: f64 = 2.0;
+const DURATION_LIMIT_SECS: f64 = 30.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMethod {
+    Isrc,
+    Search,
+}
+
+#[derive(Debug, Clone)]
+pub struct Match {
+    pub candidate: Candidate,
+    pub score: f64,
+    pub method: MatchMethod,
+}
+
+/* Lowest score a candidate needs before it is downloaded */
+pub fn threshold_from_env() -> Result<f64, String> {
+    match env::var("MATCH_THRESHOLD") {
+        Ok(threshold) if !threshold.is_empty() => parse_threshold(&threshold),
+        _ => Ok(0.6),
+    }
+}
+
+fn parse_threshold(threshold: &str) -> Result<f64, String> {
+    match threshold.parse::<f64>() {
+        Ok(value) if (0.0..=1.0).contains(&value) => Ok(value),
+        _ => Err(format!(
+            "Invalid MATCH_THRESHOLD: {} (expected a number between 0 and 1)",
+            threshold
+        )),
+    }
+}
+
+/* The top result of a search for the track's ISRC. YouTube Music puts the
+ * recording with that ISRC first, but falls back to loosely related results
+ * when it has none, so the hit still has to score as the same song. */
+pub fn isrc_match(track: &Track, candidates: &[Candidate], threshold: f64) -> Option<Match> {
+    candidates
+        .first()
+        .map(|candidate| Match {
+            candidate: candidate.clone(),
+            score: score(track, candidate),
+            method: MatchMethod::Isrc,
+        })
+        .filter(|m| m.score >= threshold)
+}
+
+/* Every candidate that reaches the threshold, best first, so the next one
+ * can be downloaded when a video is unavailable */
+pub fn ranked_matches(track: &Track, candidates: &[Candidate], threshold: f64) -> Vec<Match> {
+    let mut matches: Vec<Match> = candidates
+        .iter()
+        .map(|candidate| Match {
+            candidate: candidate.clone(),
+            score: score(track, candidate),
+            method: MatchMethod::Search,
+        })
+        .filter(|m| m.score >= threshold)
+        .collect();
+    /* Stable, so equal scores keep YouTube Music's order */
+    matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+    matches
+}
+
+/* How likely the candidate is the track, between 0 and 1 */
+pub fn score(track: &Track, candidate: &Candidate) -> f64 {
+    let title = similarity(&base_title(&track.name), &base_title(&candidate.title));
+
+    let artists = if track.artists.is_empty() {
+        0.5
+    } else {
+        let matched: Vec<bool> = track
+            .artists
+            .iter()
+            .map(|artist| {
+                candidate
+                    .artists
+                    .iter()
+                    .any(|name| same_artist(&artist.name, name))
+                    || normalize(&candidate.title).contains(&normalize(&artist.name))
+            })
+            .collect();
+        let share = matched.iter().filter(|m| **m).count() as f64 / matched.len() as f64;
+        let primary = if matched[0] { 1.0 } else { 0.0 };
+        0.7 * primary + 0.3 * share
+    };
+
+    let album = match &candidate.album {
+        Some(album) => similarity(&base_title(&track.album.name), &base_title(album)),
+        None => 0.5,
+    };
+
+    let duration = match candidate.duration {
+        Some(duration) => {
+            let diff = (duration.as_secs_f64() - track.duration_ms as f64 / 1000.0).abs();
+            let over = (diff - DURATION_TOLERANCE_SECS).max(0.0);
+            (1.0 - over / (DURATION_LIMIT_SECS - DURATION_TOLERANCE_SECS)).max(0.0)
+        }
+        None => 0.5,
+    };
+
+    let mut score = TITLE_WEIGHT * title
+        + ARTIST_WEIGHT * artists
+        + ALBUM_WEIGHT * album
+        + DURATION_WEIGHT * duration;
+    if version_keywords(&track.name) != version_keywords(&candidate.title) {
+        score *= VERSION_MISMATCH_PENALTY;
+    }
+    score
+}
+
+fn similarity(a: &str, b: &str) -> f64 {
+    normalized_levenshtein(a, b)
+}
+
+fn same_artist(a: &str, b: &str) -> bool {
+    similarity(&normalize(a), &normalize(b)) >= 0.85
+}
+
+/* Lowercase words separated by single spaces, without punctuation */
+fn normalize(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/* The title without qualifiers such as "(feat. X)", "[Remix]" or
+ * " - Remastered 2009", which are styled differently on each service */
+fn base_title(title: &str) -> String {
+    let mut base = String::new();
+    let mut depth = 0;
+    for c in title.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth = (depth - 1).max(0),
+            c if depth == 0 => base.push(c),
+            _ => {}
+        }
+    }
+    let base = base.split(" - ").next().unwrap_or_default();
+
+    let words: Vec<&str> = base.split_whitespace().collect();
+    let end = words
+        .iter()
+        .position(|word| {
+            matches!(
+                word.to_lowercase().as_str(),
+                "feat." | "feat" | "ft." | "ft"
+            )
+        })
+        .unwrap_or(words.len());
+    normalize(&words[..end].join(" "))
+}
+
+fn version_keywords(title: &str) -> Vec<&'static str> {
+    let title = format!(" {} ", normalize(title));
+    VERSION_KEYWORDS
+        .iter()
+        .filter(|keyword| title.contains(&format!(" {} ", keyword)))
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use spotify::{Album, Artist, ExternalIds};
+
+    use super::*;
+
+    fn track(name: &str, artists: &[&str], album: &str, duration_secs: u64) -> Track {
+        let artists: Vec<Artist> = artists
+            .iter()
+            .map(|name| Artist {
+                id: name.to_lowercase(),
+                name: name.to_string(),
+                genres: vec![],
+            })
+            .collect();
+        Track {
+            id: "track".to_string(),
+            name: name.to_string(),
+            track_number: 1,
+            disc_number: 1,
+            duration_ms: duration_secs * 1000,
+            explicit: false,
+            external_ids: ExternalIds::default(),
+            album: Album {
+                id: "album".to_string(),
+                name: album.to_string(),
+                album_type: "album".to_string(),
+                release_date: "2000-01-01".to_string(),
+                release_date_precision: "day".to_string(),
+                total_tracks: 10,
+                artists: artists[..1].to_vec(),
+                images: vec![],
+                genres: vec![],
+                label: None,
+                copyrights: vec![],
+                tracks: None,
+            },
+            artists,
+        }
+    }
+
+    fn candidate(
+        video_id: &str,
+        title: &str,
+        artists: &[&str],
+        album: Option<&str>,
+        duration_secs: u64,
+    ) -> Candidate {
+        Candidate {
+            video_id: video_id.to_string(),
+            title: title.to_string(),
+            artists: artists.iter().map(|artist| artist.to_string()).collect(),
+            album: album.map(|album| album.to_string()),
+            duration: Some(Duration::from_secs(duration_secs)),
+            explicit: false,
+        }
+    }
+
+    struct Case {
+        name: &'static str,
+        track: Track,
+        candidates: Vec<Candidate>,
+        expected: Option<&'static str>,
+    }
+
+    #[test]
+    fn picks_the_right_candidate() {
+        let cases = vec![
+            Case {
+                name: "studio version over live",
+                track: track("Paranoid Android", &["Radiohead"], "OK Computer", 387),
+                candidates: vec![
+                    candidate("live", "Paranoid Android (Live)", &["Radiohead"], None, 433),
+                    candidate(
+                        "studio",
+                        "Paranoid Android",
+                        &["Radiohead"],
+                        Some("OK Computer"),
+                        387,
+                    ),
+                ],
+                expected: Some("studio"),
+            },
+            Case {
+                name: "live version when the track is live",
+                track: track(
+                    "Paranoid Android - Live",
+                    &["Radiohead"],
+                    "I Might Be Wrong",
+                    433,
+                ),
+                candidates: vec![
+                    candidate(
+                        "studio",
+                        "Paranoid Android",
+                        &["Radiohead"],
+                        Some("OK Computer"),
+                        387,
+                    ),
+                    candidate("live", "Paranoid Android (Live)", &["Radiohead"], None, 434),
+                ],
+                expected: Some("live"),
+            },
+            Case {
+                name: "live only is rejected",
+                track: track("Paranoid Android", &["Radiohead"], "OK Computer", 387),
+                candidates: vec![candidate(
+                    "live",
+                    "Paranoid Android (Live at Glastonbury)",
+                    &["Radiohead"],
+                    None,
+                    440,
+                )],
+                expected: None,
+            },
+            Case {
+                name: "original over remix",
+                track: track("Blinding Lights", &["The Weeknd"], "After Hours", 200),
+                candidates: vec![
+                    candidate(
+                        "remix",
+                        "Blinding Lights (Chromatics Remix)",
+                        &["The Weeknd", "Chromatics"],
+                        None,
+                        360,
+                    ),
+                    candidate(
+                        "original",
+                        "Blinding Lights",
+                        &["The Weeknd"],
+                        Some("After Hours"),
+                        201,
+                    ),
+                ],
+                expected: Some("original"),
+            },
+            Case {
+                name: "remix when the track is a remix",
+                track: track(
+                    "Old Town Road (feat. Billy Ray Cyrus) - Remix",
+                    &["Lil Nas X", "Billy Ray Cyrus"],
+                    "7 EP",
+                    157,
+                ),
+                candidates: vec![
+                    candidate(
+                        "original",
+                        "Old Town Road",
+                        &["Lil Nas X"],
+                        Some("7 EP"),
+                        113,
+                    ),
+                    candidate(
+                        "remix",
+                        "Old Town Road (feat. Billy Ray Cyrus) [Remix]",
+                        &["Lil Nas X"],
+                        Some("7 EP"),
+                        158,
+                    ),
+                ],
+                expected: Some("remix"),
+            },
+            Case {
+                name: "original artist over cover",
+                track: track("Hallelujah", &["Jeff Buckley"], "Grace", 413),
+                candidates: vec![
+                    candidate(
+                        "cover",
+                        "Hallelujah",
+                        &["Pentatonix"],
+                        Some("A Pentatonix Christmas"),
+                        270,
+                    ),
+                    candidate(
+                        "original",
+                        "Hallelujah",
+                        &["Jeff Buckley"],
+                        Some("Grace"),
+                        414,
+                    ),
+                ],
+                expected: Some("original"),
+            },
+            Case {
+                name: "cover only is rejected",
+                track: track("Hallelujah", &["Jeff Buckley"], "Grace", 413),
+                candidates: vec![candidate(
+                    "cover",
+                    "Hallelujah",
+                    &["Pentatonix"],
+                    Some("A Pentatonix Christmas"),
+                    270,
+                )],
+                expected: None,
+            },
+            Case {
+                name: "explicitly labelled cover is rejected",
+                track: track("Creep", &["Radiohead"], "Pablo Honey", 238),
+                candidates: vec![candidate(
+                    "cover",
+                    "Creep (Cover)",
+                    &["Radiohead Tribute Band"],
+                    None,
+                    240,
+                )],
+                expected: None,
+            },
+            Case {
+                name: "featured artist styled as a title suffix",
+                track: track(
+                    "Stay (with Justin Bieber)",
+                    &["The Kid LAROI", "Justin Bieber"],
+                    "F*CK LOVE 3: OVER YOU",
+                    141,
+                ),
+                candidates: vec![candidate(
+                    "stay",
+                    "Stay",
+                    &["The Kid LAROI", "Justin Bieber"],
+                    Some("F*CK LOVE 3: OVER YOU"),
+                    142,
+                )],
+                expected: Some("stay"),
+            },
+            Case {
+                name: "featured artist only in the candidate title",
+                track: track("Nice For What", &["Drake"], "Scorpion", 210),
+                candidates: vec![candidate(
+                    "feat",
+                    "Nice For What (feat. Big Freedia)",
+                    &["Drake"],
+                    Some("Scorpion"),
+                    211,
+                )],
+                expected: Some("feat"),
+            },
+            Case {
+                name: "remaster suffix is ignored",
+                track: track(
+                    "Here Comes The Sun - Remastered 2009",
+                    &["The Beatles"],
+                    "Abbey Road (Remastered)",
+                    185,
+                ),
+                candidates: vec![candidate(
+                    "sun",
+                    "Here Comes The Sun (Remastered 2009)",
+                    &["The Beatles"],
+                    Some("Abbey Road (Remastered)"),
+                    186,
+                )],
+                expected: Some("sun"),
+            },
+            Case {
+                name: "different song is rejected",
+                track: track("Karma Police", &["Radiohead"], "OK Computer", 264),
+                candidates: vec![candidate(
+                    "other",
+                    "No Surprises",
+                    &["Radiohead"],
+                    Some("OK Computer"),
+                    229,
+                )],
+                expected: None,
+            },
+            Case {
+                name: "title words that look like versions but belong to the song",
+                track: track("Live Forever", &["Oasis"], "Definitely Maybe", 276),
+                candidates: vec![candidate(
+                    "oasis",
+                    "Live Forever",
+                    &["Oasis"],
+                    Some("Definitely Maybe"),
+                    277,
+                )],
+                expected: Some("oasis"),
+            },
+        ];
+
+        for case in cases {
+            let result = ranked_matches(&case.track, &case.candidates, 0.6)
+                .into_iter()
+                .next();
+            assert_eq!(
+                result.as_ref().map(|m| m.candidate.video_id.as_str()),
+                case.expected,
+                "{} (score {:?})",
+                case.name,
+                result.as_ref().map(|m| m.score)
+            );
+        }
+    }
+
+    #[test]
+    fn ranks_alternate_candidates() {
+        let track = track("Paranoid Android", &["Radiohead"], "OK Computer", 387);
+        let candidates = vec![
+            candidate("live", "Paranoid Android (Live)", &["Radiohead"], None, 433),
+            candidate("upload", "Paranoid Android", &["Radiohead"], None, 390),
+            candidate(
+                "studio",
+                "Paranoid Android",
+                &["Radiohead"],
+                Some("OK Computer"),
+                387,
+            ),
+        ];
+        let ranked: Vec<String> = ranked_matches(&track, &candidates, 0.6)
+            .into_iter()
+            .map(|m| m.candidate.video_id)
+            .collect();
+        assert_eq!(ranked, vec!["studio", "upload"]);
+    }
+
+    #[test]
+    fn accepts_isrc_hit_only_when_it_is_the_same_song() {
+        let track = track("Paranoid Android", &["Radiohead"], "OK Computer", 387);
+        let hit = candidate(
+            "studio",
+            "Paranoid Android",
+            &["Radiohead"],
+            Some("OK Computer"),
+            387,
+        );
+        let unrelated = candidate(
+            "other",
+            "Everything In Its Right Place",
+            &["Radiohead"],
+            Some("Kid A"),
+            251,
+        );
+
+        let found = isrc_match(&track, &[hit.clone(), unrelated.clone()], 0.6).unwrap();
+        assert_eq!(found.candidate.video_id, "studio");
+        assert_eq!(found.method, MatchMethod::Isrc);
+
+        /* Only the top result counts as the ISRC hit */
+        assert!(isrc_match(&track, &[unrelated, hit], 0.6).is_none());
+        assert!(isrc_match(&track, &[], 0.6).is_none());
+    }
+
+    #[test]
+    fn strips_title_qualifiers() {
+        assert_eq!(base_title("Stay (with Justin Bieber)"), "stay");
+        assert_eq!(
+            base_title("Old Town Road (feat. Billy Ray Cyrus) - Remix"),
+            "old town road"
+        );
+        assert_eq!(
+            base_title("Nice For What feat. Big Freedia"),
+            "nice for what"
+        );
+        assert_eq!(
+            base_title("Here Comes The Sun - Remastered 2009"),
+            "here comes the sun"
+        );
+    }
+
+    #[test]
+    fn accepts_thresholds_between_zero_and_one() {
+        assert_eq!(parse_threshold("0.75"), Ok(0.75));
+        assert_eq!(parse_threshold("1"), Ok(1.0));
+        for threshold in ["high", "1.5", "-0.1", "NaN"] {
+            assert!(parse_threshold(threshold).is_err(), "{}", threshold);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use id3::{Tag, TagLike};
+
+use library::{unique_path, PathFields, PathTemplate};
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub renamed: usize,
+    pub skipped: usize,
+}
+
+/* Files written before path templates were named after the SHA-256 of the
+ * track name, e.g. "Artist/Album/<64 hex digits>.mp3" */
+fn is_hashed(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "mp3")
+        && path.file_stem().is_some_and(|stem| {
+            stem.len() == 64
+                && stem
+                    .to_string_lossy()
+                    .chars()
+                    .all(|c| c.is_ascii_hexdigit())
+        })
+}
+
+fn hashed_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            hashed_files(&path, files)?;
+        } else if is_hashed(&path) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/* The template fields, as far as the tags the old downloader wrote go */
+fn fields_from_tag(tag: &Tag) -> Option<PathFields> {
+    let title = tag.title()?.to_string();
+    let artist = tag.artist().unwrap_or_default().to_string();
+    Some(PathFields {
+        album_artist: tag.album_artist().unwrap_or(&artist).to_string(),
+        artists: artist.clone(),
+        artist,
+        album: tag.album().unwrap_or_default().to_string(),
+        title,
+        year: tag
+            .year()
+            .or_else(|| tag.date_recorded().map(|date| date.year))
+            .map(|year| year.to_string())
+            .unwrap_or_default(),
+        disc: tag.disc().unwrap_or(1),
+        track: tag.track().unwrap_or(0),
+        ext: "mp3".to_string(),
+    })
+}
+
+/* Remove directories left empty by a rename, up to `root` */
+fn remove_empty_parents(path: &Path, root: &Path) {
+    let mut dir = path.parent();
+    while let Some(current) = dir {
+        if current == root || fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+/* Rename every hashed file under `music_home` to its templated path using
+ * its ID3 tags. With `dry_run`, only print what would be renamed. The files
+ * are left out of the index; `existing_download` adds each one once a job
+ * comes across its track. */
+pub fn migrate(music_home: &Path, template: &PathTemplate, dry_run: bool) -> io::Result<Summary> {
+    let mut files = vec![];
+    hashed_files(music_home, &mut files)?;
+    files.sort();
+
+    let mut summary = Summary::default();
+    for file in files {
+        let fields = match Tag::read_from_path(&file)
+            .ok()
+            .as_ref()
+            .and_then(fields_from_tag)
+        {
+            Some(fields) => fields,
+            None => {
+                println!("Skipping {}: missing tags", file.display());
+                summary.skipped += 1;
+                continue;
+            }
+        };
+
+        let target = unique_path(&music_home.join(template.render(&fields)), |path| {
+            path == file
+        });
+        if target == file {
+            continue;
+        }
+        println!("{} -> {}", file.display(), target.display());
+
+        if !dry_run {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(&file, &target)?;
+            remove_empty_parents(&file, music_home);
+        }
+        summary.renamed += 1;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use id3::Version;
+
+    use super::*;
+
+    fn write_tagged(path: &Path, title: &str, track: u32) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, []).unwrap();
+        let mut tag = Tag::new();
+        tag.set_album("OK Computer");
+        tag.set_artist("Radiohead");
+        tag.set_title(title);
+        tag.set_year(1997);
+        tag.set_track(track);
+        tag.write_to_path(path, Version::Id3v24).unwrap();
+    }
+
+    #[test]
+    fn renames_hashed_files_from_tags() {
+        let music_home = env::temp_dir().join(format!("downloader-migrate-{}", std::process::id()));
+        let album = music_home.join("Radiohead").join("OK Computer");
+        let hashed = album.join(format!("{}.mp3", "a".repeat(64)));
+        let duplicate = album.join(format!("{}.mp3", "b".repeat(64)));
+        let untagged = album.join(format!("{}.mp3", "c".repeat(64)));
+        write_tagged(&hashed, "Paranoid Android", 2);
+        write_tagged(&duplicate, "Paranoid Android", 2);
+        fs::write(&untagged, []).unwrap();
+        fs::write(album.join("cover.jpg"), []).unwrap();
+
+        let template =
+            PathTemplate::parse("{album_artist}/{album} ({year})/{track:02} {title}.{ext}")
+                .unwrap();
+        let summary = migrate(&music_home, &template, false).unwrap();
+
+        assert_eq!(
+            summary,
+            Summary {
+                renamed: 2,
+                skipped: 1
+            }
+        );
+        let renamed = music_home.join("Radiohead").join("OK Computer (1997)");
+        assert!(renamed.join("02 Paranoid Android.mp3").exists());
+        assert!(renamed.join("02 Paranoid Android (2).mp3").exists());
+        assert!(!hashed.exists());
+        assert!(untagged.exists());
+
+        fs::remove_dir_all(&music_home).unwrap();
+    }
+
+    #[test]
+    fn dry_run_leaves_files_in_place() {
+        let music_home = env::temp_dir().join(format!("downloader-dry-run-{}", std::process::id()));
+        let hashed = music_home
+            .join("Radiohead")
+            .join("OK Computer")
+            .join(format!("{}.mp3", "d".repeat(64)));
+        write_tagged(&hashed, "Airbag", 1);
+
+        let template = PathTemplate::parse(library::DEFAULT_TEMPLATE).unwrap();
+        let summary = migrate(&music_home, &template, true).unwrap();
+
+        assert_eq!(summary.renamed, 1);
+        assert!(hashed.exists());
+
+        fs::remove_dir_all(&music_home).unwrap();
+    }
+}
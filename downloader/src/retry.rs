@@ -0,0 +1,152 @@
+use std::env;
+use std::fmt::Display;
+use std::future::Future;
+
+use tokio::time::{sleep, Duration};
+
+/* Waited before the first retry, and doubled before each one after it */
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/* Longest wait between two attempts, however many retries came before */
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/* Keeps a misconfigured job from retrying a single track for hours */
+const MAX_RETRIES: u32 = 10;
+
+/* How often failures that may pass are tried again */
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl Retry {
+    pub fn from_env() -> Result<Retry, String> {
+        let retries = match env::var("DOWNLOAD_RETRIES") {
+            Ok(retries) if !retries.is_empty() => match retries.parse() {
+                Ok(value) if value <= MAX_RETRIES => value,
+                _ => {
+                    return Err(format!(
+                        "Invalid DOWNLOAD_RETRIES: {} (expected at most {})",
+                        retries, MAX_RETRIES
+                    ))
+                }
+            },
+            _ => 3,
+        };
+        Ok(Retry {
+            retries,
+            delay: RETRY_DELAY,
+        })
+    }
+
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(MAX_RETRY_DELAY)
+    }
+
+    /* Run `attempt` until it succeeds, fails with an error `transient` says
+     * won't pass, or has been retried `retries` times */
+    pub async fn run<T, E, F, Fut>(
+        &self,
+        what: &str,
+        transient: impl Fn(&E) -> bool,
+        mut attempt: F,
+    ) -> Result<T, E>
+    where
+        E: Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut retry = 0;
+        loop {
+            match attempt().await {
+                Err(e) if retry < self.retries && transient(&e) => {
+                    let delay = self.backoff(retry);
+                    println!(
+                        "{} failed, retrying in {}s: {}",
+                        what,
+                        delay.as_secs_f64(),
+                        e
+                    );
+                    sleep(delay).await;
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn doubles_the_delay_up_to_a_minute() {
+        let retry = Retry {
+            retries: 3,
+            delay: Duration::from_secs(5),
+        };
+        assert_eq!(retry.backoff(0), Duration::from_secs(5));
+        assert_eq!(retry.backoff(2), Duration::from_secs(20));
+        assert_eq!(retry.backoff(4), Duration::from_secs(60));
+        assert_eq!(retry.backoff(40), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors_only() {
+        let retry = Retry {
+            retries: 2,
+            delay: Duration::from_millis(1),
+        };
+
+        let attempts = Cell::new(0);
+        let result: Result<(), String> = retry
+            .run(
+                "Test",
+                |_| true,
+                || async {
+                    attempts.set(attempts.get() + 1);
+                    Err("timed out".to_string())
+                },
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 3);
+
+        attempts.set(0);
+        let result = retry
+            .run(
+                "Test",
+                |_: &String| true,
+                || async {
+                    attempts.set(attempts.get() + 1);
+                    match attempts.get() {
+                        1 => Err("timed out".to_string()),
+                        _ => Ok("done"),
+                    }
+                },
+            )
+            .await;
+        assert_eq!(result, Ok("done"));
+        assert_eq!(attempts.get(), 2);
+
+        attempts.set(0);
+        let result: Result<(), String> = retry
+            .run(
+                "Test",
+                |_| false,
+                || async {
+                    attempts.set(attempts.get() + 1);
+                    Err("gone".to_string())
+                },
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+}
@@ -0,0 +1,871 @@
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+use id3::frame::{
+    Content, ExtendedText, PictureType, SynchronisedLyrics, SynchronisedLyricsType, TimestampFormat,
+};
+use id3::{frame, Frame, TagLike, Timestamp, Version};
+use lofty::config::{ParseOptions, WriteOptions};
+use lofty::file::{AudioFile, TaggedFileExt};
+use lofty::mp4::{Atom, AtomData, AtomIdent, Ilst, Mp4File};
+use lofty::ogg::{OggPictureStorage, VorbisComments};
+use lofty::picture::{MimeType, Picture};
+use lofty::probe::Probe;
+use lofty::tag::{Accessor, TagExt, TagType};
+
+use library::OutputFormat;
+use spotify::{Artist, Track};
+
+use crate::details::Details;
+use crate::loudness::ReplayGain;
+use crate::lyrics::Lyrics;
+
+/* Identifies which track a file holds, so a different track that renders to
+ * the same path gets its own file instead of overwriting it */
+pub const TRACK_ID_TAG: &str = "SPOTIFY_TRACK_ID";
+const ALBUM_ID_TAG: &str = "SPOTIFY_ALBUM_ID";
+const ARTIST_ID_TAG: &str = "SPOTIFY_ARTIST_ID";
+
+/* Every track artist as a separate value, next to the joined display name */
+const ARTISTS_TAG: &str = "ARTISTS";
+
+/* MP4 has no standard atom for custom values, so they go into freeform
+ * atoms under the namespace iTunes uses */
+const MP4_FREEFORM_MEAN: &str = "com.apple.iTunes";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cover {
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+/* Everything written into a file's tags, whatever the format */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub title: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    pub album_artists: Vec<String>,
+    pub compilation: bool,
+    /* "1997", "1997-05" or "1997-05-28", depending on what Spotify knows */
+    pub release_date: String,
+    pub track_number: u32,
+    pub total_tracks: Option<u32>,
+    pub disc_number: u32,
+    pub total_discs: Option<u32>,
+    pub genres: Vec<String>,
+    pub isrc: Option<String>,
+    pub label: Option<String>,
+    pub copyright: Option<String>,
+    pub track_id: String,
+    pub album_id: String,
+    pub artist_id: Option<String>,
+    pub cover: Option<Cover>,
+    pub lyrics: Option<Lyrics>,
+    pub replay_gain: Option<ReplayGain>,
+}
+
+impl Metadata {
+    pub fn from_track(
+        track: &Track,
+        details: &Details,
+        cover: Option<Cover>,
+        lyrics: Option<Lyrics>,
+        replay_gain: Option<ReplayGain>,
+    ) -> Metadata {
+        let album = details.album(track);
+        Metadata {
+            title: track.name.clone(),
+            artists: names(&track.artists),
+            album: track.album.name.clone(),
+            album_artists: names(&track.album.artists),
+            compilation: track.album.album_type == "compilation",
+            release_date: track.album.release_date.clone(),
+            track_number: track.track_number,
+            total_tracks: Some(track.album.total_tracks).filter(|total| *total > 0),
+            disc_number: track.disc_number,
+            total_discs: album.and_then(|album| album.total_discs),
+            genres: details.genres(track),
+            isrc: track.external_ids.isrc.clone(),
+            label: album.and_then(|album| album.label.clone()),
+            copyright: album.and_then(|album| album.copyright.clone()),
+            track_id: track.id.clone(),
+            album_id: track.album.id.clone(),
+            artist_id: track.artists.first().map(|artist| artist.id.clone()),
+            cover,
+            lyrics,
+            replay_gain,
+        }
+    }
+
+    /* How the artists read in players that show a single artist */
+    fn artist(&self) -> String {
+        self.artists.join(", ")
+    }
+
+    fn album_artist(&self) -> String {
+        self.album_artists.join(", ")
+    }
+
+    fn release_date_parts(&self) -> Vec<u8> {
+        self.release_date
+            .split('-')
+            .skip(1)
+            .filter_map(|part| part.parse().ok())
+            .collect()
+    }
+
+    fn year(&self) -> Option<i32> {
+        self.release_date.split('-').next()?.parse().ok()
+    }
+}
+
+fn names(artists: &[Artist]) -> Vec<String> {
+    artists.iter().map(|artist| artist.name.clone()).collect()
+}
+
+#[derive(Debug)]
+pub enum TagError {
+    Id3(id3::Error),
+    Lofty(lofty::error::LoftyError),
+    /* The file has no tags of the expected kind to update */
+    Missing,
+}
+
+impl fmt::Display for TagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TagError::Id3(e) => write!(f, "Failed to write ID3 tag: {}", e),
+            TagError::Lofty(e) => write!(f, "Failed to write tags: {}", e),
+            TagError::Missing => write!(f, "File has no tags to update"),
+        }
+    }
+}
+
+impl std::error::Error for TagError {}
+
+impl From<id3::Error> for TagError {
+    fn from(e: id3::Error) -> Self {
+        TagError::Id3(e)
+    }
+}
+
+impl From<lofty::error::LoftyError> for TagError {
+    fn from(e: lofty::error::LoftyError) -> Self {
+        TagError::Lofty(e)
+    }
+}
+
+pub trait TagWriter {
+    /* Replace the file's tags with the metadata */
+    fn write(&self, path: &Path, metadata: &Metadata) -> Result<(), TagError>;
+
+    /* The Spotify track id a previous `write` stored in the file */
+    fn track_id(&self, path: &Path) -> Option<String>;
+
+    /* Replace the ReplayGain tags of a file `write` already tagged, keeping
+     * every other tag */
+    fn write_replay_gain(&self, path: &Path, gain: &ReplayGain) -> Result<(), TagError>;
+
+    /* Mark a file found in the library as holding the track, keeping every
+     * other tag. Unlike `write_replay_gain`, the file may have no tags yet. */
+    fn write_track_id(&self, path: &Path, track_id: &str) -> Result<(), TagError>;
+}
+
+pub fn writer_for(format: OutputFormat) -> Box<dyn TagWriter> {
+    match format {
+        OutputFormat::Mp3 => Box::new(Id3Writer),
+        OutputFormat::Opus => Box::new(VorbisWriter { opus: true }),
+        OutputFormat::Flac => Box::new(VorbisWriter { opus: false }),
+        OutputFormat::M4a => Box::new(Mp4Writer),
+    }
+}
+
+fn picture(cover: &Cover) -> Picture {
+    Picture::new_unchecked(
+        lofty::picture::PictureType::CoverFront,
+        Some(MimeType::from_str(&cover.mime_type)),
+        Some("Cover".to_string()),
+        cover.data.clone(),
+    )
+}
+
+fn read_options() -> ParseOptions {
+    ParseOptions::new().read_properties(false)
+}
+
+/* ID3v2.4 for MP3 */
+pub struct Id3Writer;
+
+impl TagWriter for Id3Writer {
+    fn write(&self, path: &Path, metadata: &Metadata) -> Result<(), TagError> {
+        let mut tag = id3::Tag::new();
+        tag.set_title(&metadata.title);
+        tag.set_artist(metadata.artist());
+        tag.set_album(&metadata.album);
+        tag.set_album_artist(metadata.album_artist());
+        if metadata.compilation {
+            tag.set_text("TCMP", "1");
+        }
+        if let Some(year) = metadata.year() {
+            let parts = metadata.release_date_parts();
+            tag.set_date_recorded(Timestamp {
+                year,
+                month: parts.first().copied(),
+                day: parts.get(1).copied(),
+                hour: None,
+                minute: None,
+                second: None,
+            });
+        }
+        tag.set_track(metadata.track_number);
+        if let Some(total) = metadata.total_tracks {
+            tag.set_total_tracks(total);
+        }
+        tag.set_disc(metadata.disc_number);
+        if let Some(total) = metadata.total_discs {
+            tag.set_total_discs(total);
+        }
+        /* ID3v2.4 separates multiple values with a null byte */
+        if !metadata.genres.is_empty() {
+            tag.set_text_values("TCON", metadata.genres.clone());
+        }
+        if let Some(isrc) = &metadata.isrc {
+            tag.set_text("TSRC", isrc);
+        }
+        if let Some(label) = &metadata.label {
+            tag.set_text("TPUB", label);
+        }
+        if let Some(copyright) = &metadata.copyright {
+            tag.set_text("TCOP", copyright);
+        }
+
+        let mut extended = vec![
+            (ARTISTS_TAG, metadata.artists.join("\0")),
+            (TRACK_ID_TAG, metadata.track_id.clone()),
+            (ALBUM_ID_TAG, metadata.album_id.clone()),
+        ];
+        if let Some(artist_id) = &metadata.artist_id {
+            extended.push((ARTIST_ID_TAG, artist_id.clone()));
+        }
+        for (description, value) in extended {
+            tag.add_frame(ExtendedText {
+                description: description.to_string(),
+                value,
+            });
+        }
+
+        if let Some(cover) = &metadata.cover {
+            let picture = frame::Picture {
+                mime_type: cover.mime_type.clone(),
+                picture_type: PictureType::CoverFront,
+                description: "Cover".to_string(),
+                data: cover.data.clone(),
+            };
+            tag.add_frame(Frame::with_content("APIC", Content::Picture(picture)));
+        }
+
+        /* The language of the lyrics isn't known, which ID3 spells "XXX" */
+        if let Some(lyrics) = &metadata.lyrics {
+            tag.add_frame(frame::Lyrics {
+                lang: "XXX".to_string(),
+                description: String::new(),
+                text: lyrics.plain.clone(),
+            });
+            if !lyrics.synced.is_empty() {
+                tag.add_frame(SynchronisedLyrics {
+                    lang: "XXX".to_string(),
+                    timestamp_format: TimestampFormat::Ms,
+                    content_type: SynchronisedLyricsType::Lyrics,
+                    description: String::new(),
+                    content: lyrics
+                        .synced
+                        .iter()
+                        .map(|line| (line.time, line.text.clone()))
+                        .collect(),
+                });
+            }
+        }
+
+        if let Some(gain) = &metadata.replay_gain {
+            set_id3_replay_gain(&mut tag, gain);
+        }
+
+        tag.write_to_path(path, Version::Id3v24)?;
+        Ok(())
+    }
+
+    fn track_id(&self, path: &Path) -> Option<String> {
+        id3::Tag::read_from_path(path)
+            .ok()?
+            .extended_texts()
+            .find(|text| text.description == TRACK_ID_TAG)
+            .map(|text| text.value.clone())
+    }
+
+    fn write_replay_gain(&self, path: &Path, gain: &ReplayGain) -> Result<(), TagError> {
+        let mut tag = id3::Tag::read_from_path(path)?;
+        set_id3_replay_gain(&mut tag, gain);
+        tag.write_to_path(path, Version::Id3v24)?;
+        Ok(())
+    }
+
+    fn write_track_id(&self, path: &Path, track_id: &str) -> Result<(), TagError> {
+        let mut tag = match id3::Tag::read_from_path(path) {
+            Ok(tag) => tag,
+            Err(e) if matches!(e.kind, id3::ErrorKind::NoTag) => id3::Tag::new(),
+            Err(e) => return Err(e.into()),
+        };
+        tag.remove_extended_text(Some(TRACK_ID_TAG), None);
+        tag.add_frame(ExtendedText {
+            description: TRACK_ID_TAG.to_string(),
+            value: track_id.to_string(),
+        });
+        tag.write_to_path(path, Version::Id3v24)?;
+        Ok(())
+    }
+}
+
+fn set_id3_replay_gain(tag: &mut id3::Tag, gain: &ReplayGain) {
+    for (description, value) in gain.fields() {
+        tag.remove_extended_text(Some(description), None);
+        tag.add_frame(ExtendedText {
+            description: description.to_string(),
+            value,
+        });
+    }
+}
+
+/* Vorbis comments for Opus, FLAC and Ogg Vorbis */
+pub struct VorbisWriter {
+    /* Opus has gain tags of its own */
+    opus: bool,
+}
+
+impl VorbisWriter {
+    fn set_replay_gain(&self, comments: &mut VorbisComments, gain: &ReplayGain) {
+        let fields = if self.opus {
+            gain.r128_fields()
+        } else {
+            gain.fields()
+        };
+        for (key, value) in fields {
+            comments.insert(key.to_string(), value);
+        }
+    }
+}
+
+impl TagWriter for VorbisWriter {
+    fn write(&self, path: &Path, metadata: &Metadata) -> Result<(), TagError> {
+        let mut comments = VorbisComments::new();
+        let mut fields = vec![
+            ("TITLE", metadata.title.clone()),
+            ("ARTIST", metadata.artist()),
+            ("ALBUM", metadata.album.clone()),
+            ("ALBUMARTIST", metadata.album_artist()),
+            ("DATE", metadata.release_date.clone()),
+            ("TRACKNUMBER", metadata.track_number.to_string()),
+            ("DISCNUMBER", metadata.disc_number.to_string()),
+            (TRACK_ID_TAG, metadata.track_id.clone()),
+            (ALBUM_ID_TAG, metadata.album_id.clone()),
+        ];
+        if metadata.compilation {
+            fields.push(("COMPILATION", "1".to_string()));
+        }
+        let optional = [
+            (
+                "TRACKTOTAL",
+                metadata.total_tracks.map(|total| total.to_string()),
+            ),
+            (
+                "DISCTOTAL",
+                metadata.total_discs.map(|total| total.to_string()),
+            ),
+            ("ISRC", metadata.isrc.clone()),
+            ("LABEL", metadata.label.clone()),
+            ("COPYRIGHT", metadata.copyright.clone()),
+            (ARTIST_ID_TAG, metadata.artist_id.clone()),
+            ("LYRICS", metadata.lyrics.as_ref().map(Lyrics::lrc)),
+        ];
+        fields.extend(
+            optional
+                .into_iter()
+                .filter_map(|(key, value)| value.map(|value| (key, value))),
+        );
+        for (key, value) in fields {
+            comments.insert(key.to_string(), value);
+        }
+
+        /* Vorbis comments hold multiple values by repeating the field */
+        for artist in &metadata.artists {
+            comments.push(ARTISTS_TAG.to_string(), artist.clone());
+        }
+        for genre in &metadata.genres {
+            comments.push("GENRE".to_string(), genre.clone());
+        }
+
+        if let Some(cover) = &metadata.cover {
+            comments.insert_picture(picture(cover), None)?;
+        }
+
+        if let Some(gain) = &metadata.replay_gain {
+            self.set_replay_gain(&mut comments, gain);
+        }
+
+        comments.save_to_path(path, WriteOptions::default())?;
+        Ok(())
+    }
+
+    fn track_id(&self, path: &Path) -> Option<String> {
+        let file = Probe::open(path)
+            .ok()?
+            .options(read_options())
+            .read()
+            .ok()?;
+        let comments = VorbisComments::from(file.tag(TagType::VorbisComments)?.clone());
+        comments.get(TRACK_ID_TAG).map(str::to_string)
+    }
+
+    fn write_replay_gain(&self, path: &Path, gain: &ReplayGain) -> Result<(), TagError> {
+        let file = Probe::open(path)?.options(read_options()).read()?;
+        let tag = file.tag(TagType::VorbisComments).ok_or(TagError::Missing)?;
+        let mut comments = VorbisComments::from(tag.clone());
+        self.set_replay_gain(&mut comments, gain);
+        comments.save_to_path(path, WriteOptions::default())?;
+        Ok(())
+    }
+
+    fn write_track_id(&self, path: &Path, track_id: &str) -> Result<(), TagError> {
+        let file = Probe::open(path)?.options(read_options()).read()?;
+        let mut comments = file
+            .tag(TagType::VorbisComments)
+            .map(|tag| VorbisComments::from(tag.clone()))
+            .unwrap_or_default();
+        comments.insert(TRACK_ID_TAG.to_string(), track_id.to_string());
+        comments.save_to_path(path, WriteOptions::default())?;
+        Ok(())
+    }
+}
+
+/* iTunes style atoms for M4A */
+pub struct Mp4Writer;
+
+fn freeform(name: &str) -> AtomIdent<'static> {
+    AtomIdent::Freeform {
+        mean: MP4_FREEFORM_MEAN.into(),
+        name: name.to_string().into(),
+    }
+}
+
+impl TagWriter for Mp4Writer {
+    fn write(&self, path: &Path, metadata: &Metadata) -> Result<(), TagError> {
+        let mut ilst = Ilst::new();
+        ilst.set_title(metadata.title.clone());
+        ilst.set_artist(metadata.artist());
+        ilst.set_album(metadata.album.clone());
+        ilst.set_track(metadata.track_number);
+        if let Some(total) = metadata.total_tracks {
+            ilst.set_track_total(total);
+        }
+        ilst.set_disk(metadata.disc_number);
+        if let Some(total) = metadata.total_discs {
+            ilst.set_disk_total(total);
+        }
+        if metadata.compilation {
+            ilst.set_flag(AtomIdent::Fourcc(*b"cpil"), true);
+        }
+
+        let mut atoms = vec![
+            (AtomIdent::Fourcc(*b"aART"), metadata.album_artist()),
+            (
+                AtomIdent::Fourcc(*b"\xa9day"),
+                metadata.release_date.clone(),
+            ),
+            (freeform(TRACK_ID_TAG), metadata.track_id.clone()),
+            (freeform(ALBUM_ID_TAG), metadata.album_id.clone()),
+        ];
+        /* Players read a single genre atom, so multiple genres share it */
+        if !metadata.genres.is_empty() {
+            atoms.push((AtomIdent::Fourcc(*b"\xa9gen"), metadata.genres.join("; ")));
+        }
+        let optional = [
+            (freeform("ISRC"), metadata.isrc.clone()),
+            (freeform("LABEL"), metadata.label.clone()),
+            (AtomIdent::Fourcc(*b"cprt"), metadata.copyright.clone()),
+            (freeform(ARTIST_ID_TAG), metadata.artist_id.clone()),
+            (
+                AtomIdent::Fourcc(*b"\xa9lyr"),
+                metadata.lyrics.as_ref().map(Lyrics::lrc),
+            ),
+        ];
+        atoms.extend(
+            optional
+                .into_iter()
+                .filter_map(|(ident, value)| value.map(|value| (ident, value))),
+        );
+        for (ident, value) in atoms {
+            ilst.insert(Atom::new(ident, AtomData::UTF8(value)));
+        }
+
+        let artists = metadata
+            .artists
+            .iter()
+            .map(|artist| AtomData::UTF8(artist.clone()))
+            .collect();
+        if let Some(atom) = Atom::from_collection(freeform(ARTISTS_TAG), artists) {
+            ilst.insert(atom);
+        }
+
+        if let Some(cover) = &metadata.cover {
+            ilst.insert_picture(picture(cover));
+        }
+
+        if let Some(gain) = &metadata.replay_gain {
+            set_mp4_replay_gain(&mut ilst, gain);
+        }
+
+        ilst.save_to_path(path, WriteOptions::default())?;
+        Ok(())
+    }
+
+    fn track_id(&self, path: &Path) -> Option<String> {
+        let file = Mp4File::read_from(&mut File::open(path).ok()?, read_options()).ok()?;
+        let track_id = match file.ilst()?.get(&freeform(TRACK_ID_TAG))?.data().next()? {
+            AtomData::UTF8(track_id) => Some(track_id.clone()),
+            _ => None,
+        };
+        track_id
+    }
+
+    fn write_replay_gain(&self, path: &Path, gain: &ReplayGain) -> Result<(), TagError> {
+        let mut reader = File::open(path).map_err(lofty::error::LoftyError::from)?;
+        let file = Mp4File::read_from(&mut reader, read_options())?;
+        let mut ilst = file.ilst().ok_or(TagError::Missing)?.clone();
+        set_mp4_replay_gain(&mut ilst, gain);
+        ilst.save_to_path(path, WriteOptions::default())?;
+        Ok(())
+    }
+
+    fn write_track_id(&self, path: &Path, track_id: &str) -> Result<(), TagError> {
+        let mut reader = File::open(path).map_err(lofty::error::LoftyError::from)?;
+        let file = Mp4File::read_from(&mut reader, read_options())?;
+        let mut ilst = file.ilst().cloned().unwrap_or_default();
+        ilst.replace_atom(Atom::new(
+            freeform(TRACK_ID_TAG),
+            AtomData::UTF8(track_id.to_string()),
+        ));
+        ilst.save_to_path(path, WriteOptions::default())?;
+        Ok(())
+    }
+}
+
+fn set_mp4_replay_gain(ilst: &mut Ilst, gain: &ReplayGain) {
+    for (name, value) in gain.fields() {
+        ilst.replace_atom(Atom::new(freeform(name), AtomData::UTF8(value)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use lofty::tag::{ItemKey, Tag};
+
+    use super::*;
+    use crate::loudness::Gain;
+
+    /* Minimal silent files, one per format */
+    const FIXTURES: [(OutputFormat, &str); 4] = [
+        (OutputFormat::Mp3, "silence.mp3"),
+        (OutputFormat::Opus, "silence.opus"),
+        (OutputFormat::Flac, "silence.flac"),
+        (OutputFormat::M4a, "silence.m4a"),
+    ];
+
+    fn metadata() -> Metadata {
+        Metadata {
+            title: "Stay".to_string(),
+            artists: vec!["The Kid LAROI".to_string(), "Justin Bieber".to_string()],
+            album: "Hits of the Year".to_string(),
+            album_artists: vec!["Various Artists".to_string()],
+            compilation: true,
+            release_date: "2021-07-09".to_string(),
+            track_number: 3,
+            total_tracks: Some(40),
+            disc_number: 2,
+            total_discs: Some(2),
+            genres: vec!["pop".to_string(), "dance pop".to_string()],
+            isrc: Some("USSM12105970".to_string()),
+            label: Some("Columbia".to_string()),
+            copyright: Some("2021 Columbia Records".to_string()),
+            track_id: "5HCyWlXZPP0y6Gqq8TgA20".to_string(),
+            album_id: "6jxxHFrX7EQOHG7GTCkoVx".to_string(),
+            artist_id: Some("2tIP7SsRs7vjIcLrU85W8J".to_string()),
+            cover: Some(Cover {
+                mime_type: "image/png".to_string(),
+                data: b"\x89PNG\r\n\x1a\nnot really a png".to_vec(),
+            }),
+            lyrics: Lyrics::new(None, Some("[00:01.00] I do the same thing".to_string())),
+            replay_gain: Some(ReplayGain {
+                track: Gain {
+                    gain: -6.5,
+                    peak: 0.9,
+                },
+                album: None,
+            }),
+        }
+    }
+
+    fn copy_fixture(name: &str, test: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!(
+            "downloader-{}-{}-{}",
+            test,
+            std::process::id(),
+            name
+        ));
+        fs::copy(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures")
+                .join(name),
+            &path,
+        )
+        .unwrap();
+        path
+    }
+
+    fn read_tag(path: &Path) -> Tag {
+        Probe::open(path)
+            .unwrap()
+            .options(read_options())
+            .read()
+            .unwrap()
+            .primary_tag()
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        let metadata = metadata();
+        for (format, fixture) in FIXTURES {
+            let path = copy_fixture(fixture, "round-trip");
+            let writer = writer_for(format);
+
+            assert_eq!(writer.track_id(&path), None, "{}", fixture);
+            writer.write(&path, &metadata).unwrap();
+
+            let tag = read_tag(&path);
+            let text = |key: ItemKey| tag.get_string(&key).map(str::to_string);
+            assert_eq!(tag.title().as_deref(), Some("Stay"), "{}", fixture);
+            assert_eq!(
+                tag.artist().as_deref(),
+                Some("The Kid LAROI, Justin Bieber"),
+                "{}",
+                fixture
+            );
+            /* lofty only converts the first value of an MP4 atom */
+            let artists = if format == OutputFormat::M4a {
+                let file = Mp4File::read_from(&mut File::open(&path).unwrap(), read_options());
+                let ilst = file.unwrap().ilst().unwrap().clone();
+                ilst.get(&freeform(ARTISTS_TAG)).unwrap().data().count()
+            } else {
+                tag.get_strings(&ItemKey::TrackArtists).count()
+            };
+            assert_eq!(artists, 2, "{}", fixture);
+            assert_eq!(
+                tag.album().as_deref(),
+                Some("Hits of the Year"),
+                "{}",
+                fixture
+            );
+            assert_eq!(
+                text(ItemKey::AlbumArtist).as_deref(),
+                Some("Various Artists"),
+                "{}",
+                fixture
+            );
+            assert_eq!(
+                text(ItemKey::FlagCompilation).as_deref(),
+                Some("1"),
+                "{}",
+                fixture
+            );
+            assert_eq!(
+                text(ItemKey::RecordingDate).as_deref(),
+                Some("2021-07-09"),
+                "{}",
+                fixture
+            );
+            assert_eq!(tag.track(), Some(3), "{}", fixture);
+            assert_eq!(tag.track_total(), Some(40), "{}", fixture);
+            assert_eq!(tag.disk(), Some(2), "{}", fixture);
+            assert_eq!(tag.disk_total(), Some(2), "{}", fixture);
+            assert!(tag.genre().unwrap().starts_with("pop"), "{}", fixture);
+            assert_eq!(
+                text(ItemKey::Isrc).as_deref(),
+                Some("USSM12105970"),
+                "{}",
+                fixture
+            );
+            assert_eq!(
+                text(ItemKey::Label).as_deref(),
+                Some("Columbia"),
+                "{}",
+                fixture
+            );
+            assert_eq!(
+                text(ItemKey::CopyrightMessage).as_deref(),
+                Some("2021 Columbia Records"),
+                "{}",
+                fixture
+            );
+            assert_eq!(
+                text(ItemKey::Lyrics).as_deref(),
+                Some(if format == OutputFormat::Mp3 {
+                    "I do the same thing"
+                } else {
+                    "[00:01.00] I do the same thing"
+                }),
+                "{}",
+                fixture
+            );
+            if format == OutputFormat::Mp3 {
+                let id3 = id3::Tag::read_from_path(&path).unwrap();
+                let synced = id3.synchronised_lyrics().next().unwrap();
+                assert_eq!(
+                    synced.content,
+                    vec![(1000, "I do the same thing".to_string())]
+                );
+            }
+            assert_eq!(tag.pictures().len(), 1, "{}", fixture);
+            assert_eq!(
+                tag.pictures()[0].data(),
+                metadata.cover.as_ref().unwrap().data
+            );
+            assert_eq!(
+                writer.track_id(&path).as_deref(),
+                Some("5HCyWlXZPP0y6Gqq8TgA20"),
+                "{}",
+                fixture
+            );
+
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn rewriting_replaces_previous_tags() {
+        for (format, fixture) in FIXTURES {
+            let path = copy_fixture(fixture, "rewrite");
+            let writer = writer_for(format);
+
+            writer.write(&path, &metadata()).unwrap();
+            let mut updated = metadata();
+            updated.title = "Without You".to_string();
+            updated.cover = None;
+            writer.write(&path, &updated).unwrap();
+
+            let tag = read_tag(&path);
+            assert_eq!(tag.title().as_deref(), Some("Without You"), "{}", fixture);
+            assert!(tag.pictures().is_empty(), "{}", fixture);
+
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn adds_album_gain_to_tagged_files() {
+        for (format, fixture) in FIXTURES {
+            let path = copy_fixture(fixture, "replay-gain");
+            let writer = writer_for(format);
+            writer.write(&path, &metadata()).unwrap();
+
+            let gain = ReplayGain {
+                track: Gain {
+                    gain: -7.25,
+                    peak: 0.95,
+                },
+                album: Some(Gain {
+                    gain: -8.0,
+                    peak: 1.02,
+                }),
+            };
+            writer.write_replay_gain(&path, &gain).unwrap();
+
+            let tag = read_tag(&path);
+            if format == OutputFormat::Opus {
+                let comments = VorbisComments::from(tag.clone());
+                assert_eq!(comments.get("R128_TRACK_GAIN"), Some("-3136"));
+                assert_eq!(comments.get("R128_ALBUM_GAIN"), Some("-3328"));
+                assert_eq!(comments.get("REPLAYGAIN_TRACK_GAIN"), None);
+            } else {
+                let text = |key: ItemKey| tag.get_string(&key).map(str::to_string);
+                assert_eq!(
+                    text(ItemKey::ReplayGainTrackGain).as_deref(),
+                    Some("-7.25 dB"),
+                    "{}",
+                    fixture
+                );
+                assert_eq!(
+                    text(ItemKey::ReplayGainAlbumPeak).as_deref(),
+                    Some("1.020000"),
+                    "{}",
+                    fixture
+                );
+            }
+            /* Everything else is left as it was */
+            assert_eq!(tag.title().as_deref(), Some("Stay"), "{}", fixture);
+            assert_eq!(tag.pictures().len(), 1, "{}", fixture);
+            assert_eq!(
+                writer.track_id(&path).as_deref(),
+                Some("5HCyWlXZPP0y6Gqq8TgA20"),
+                "{}",
+                fixture
+            );
+
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn adds_track_id_to_files_with_or_without_tags() {
+        for (format, fixture) in FIXTURES {
+            let path = copy_fixture(fixture, "track-id");
+            let writer = writer_for(format);
+
+            writer
+                .write_track_id(&path, "4uLU6hMCjMI75M1A2tKUQC")
+                .unwrap();
+            assert_eq!(
+                writer.track_id(&path).as_deref(),
+                Some("4uLU6hMCjMI75M1A2tKUQC"),
+                "{}",
+                fixture
+            );
+
+            let mut untracked = metadata();
+            untracked.track_id = String::new();
+            writer.write(&path, &untracked).unwrap();
+            writer
+                .write_track_id(&path, "4uLU6hMCjMI75M1A2tKUQC")
+                .unwrap();
+            assert_eq!(
+                writer.track_id(&path).as_deref(),
+                Some("4uLU6hMCjMI75M1A2tKUQC"),
+                "{}",
+                fixture
+            );
+            assert_eq!(
+                read_tag(&path).title().as_deref(),
+                Some("Stay"),
+                "{}",
+                fixture
+            );
+
+            fs::remove_file(&path).unwrap();
+        }
+    }
+}
@@ -1,81 +1,445 @@
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
 use std::fs;
-use std::path::Path;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-use id3::frame::{Content, PictureType};
-use id3::{frame, Frame, Tag, TagLike, Version};
+use library::{
+    unique_path, unique_path_by, IndexEntry, LibraryIndex, Loudness, OutputFormat, PathFields,
+    PathTemplate,
+};
+use spotify::Track;
+use tokio::process::Command;
 
-use sha2::{Digest, Sha256};
+use crate::details::Details;
+use crate::loudness::{self, Gain, LoudnessMode, ReplayGain};
+use crate::lyrics::Lyrics;
+use crate::tagging::{writer_for, Cover, Metadata, TagWriter};
 
-use crate::spotify_client::Track;
+/* Where, in which format and at what loudness tracks are written */
+pub struct Output {
+    pub music_home: PathBuf,
+    pub template: PathTemplate,
+    pub format: OutputFormat,
+    pub loudness: LoudnessMode,
+    /* Paths tracks of this job are being written to, and by which track,
+     * so tracks downloaded at the same time never pick the same name */
+    claimed: Mutex<HashMap<PathBuf, String>>,
+}
+
+impl Output {
+    pub fn from_env() -> Result<Output, String> {
+        Ok(Output {
+            music_home: PathBuf::from(
+                env::var("MUSIC_HOME").map_err(|_| "MUSIC_HOME environment variable not set")?,
+            ),
+            template: PathTemplate::from_env().map_err(|e| e.to_string())?,
+            format: OutputFormat::from_env().map_err(|e| e.to_string())?,
+            loudness: LoudnessMode::from_env()?,
+            claimed: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+/* yt-dlp options for each format. YouTube serves Opus and AAC, so picking
+ * that stream lets yt-dlp remux it instead of transcoding it again. */
+fn format_args(format: OutputFormat) -> Vec<&'static str> {
+    match format {
+        OutputFormat::Opus => vec![
+            "-f",
+            "bestaudio[acodec=opus]/bestaudio",
+            "--audio-format",
+            "opus",
+        ],
+        OutputFormat::M4a => vec![
+            "-f",
+            "bestaudio[ext=m4a]/bestaudio",
+            "--audio-format",
+            "m4a",
+        ],
+        OutputFormat::Mp3 => vec![
+            "-f",
+            "bestaudio",
+            "--audio-format",
+            "mp3",
+            "--audio-quality",
+            "0",
+        ],
+        OutputFormat::Flac => vec!["-f", "bestaudio", "--audio-format", "flac"],
+    }
+}
+
+/* Where the track is written. A file already at that path that holds the
+ * same track is reused rather than given a new name. */
+fn track_path(track: &Track, output: &Output) -> PathBuf {
+    let writer = writer_for(output.format);
+    let templated = templated_path(track, output);
+    unique_path(&templated, |path| {
+        holds_track(writer.as_ref(), path, track, &templated)
+    })
+}
+
+/* Files are tagged with the track they hold. An untagged file at the
+ * templated path was written before tagging, or renamed by `migrate`, and is
+ * taken to be the track. */
+fn holds_track(writer: &dyn TagWriter, path: &Path, track: &Track, templated: &Path) -> bool {
+    match writer.track_id(path) {
+        Some(track_id) => track_id == track.id,
+        None => path == templated,
+    }
+}
+
+fn templated_path(track: &Track, output: &Output) -> PathBuf {
+    output.music_home.join(
+        output
+            .template
+            .render(&PathFields::from_track(track, output.format.extension())),
+    )
+}
+
+/* Like `track_path`, but also steering clear of paths that other tracks
+ * of the job claimed before writing to them. The path is claimed for the
+ * track in turn. */
+fn claim_path(track: &Track, output: &Output) -> PathBuf {
+    let writer = writer_for(output.format);
+    let templated = templated_path(track, output);
+    let mut claimed = output.claimed.lock().unwrap();
+    let path = unique_path_by(&templated, |path| match claimed.get(path) {
+        Some(track_id) => *track_id == track.id,
+        None => !path.exists() || holds_track(writer.as_ref(), path, track, &templated),
+    });
+    claimed.insert(path.clone(), track.id.clone());
+    path
+}
+
+/* The file the track was already downloaded to, if any. Files written
+ * before the index existed are found at their path and added to it, and
+ * tagged with the track if they weren't. */
+pub fn existing_download(track: &Track, output: &Output, index: &LibraryIndex) -> Option<PathBuf> {
+    let isrc = track.external_ids.isrc.as_deref();
+    if let Some(entry) = index.find(&track.id, isrc) {
+        return Some(index.full_path(&entry));
+    }
+
+    let path = track_path(track, output);
+    if !path.exists() {
+        return None;
+    }
+    let writer = writer_for(output.format);
+    if writer.track_id(&path).is_none() {
+        if let Err(e) = writer.write_track_id(&path, &track.id) {
+            println!("Failed to tag {}: {}", path.display(), e);
+        }
+    }
+    if let Err(e) = index.insert(&index_entry(track, output, &path, None)) {
+        println!("Failed to index {}: {}", path.display(), e);
+    }
+    Some(path)
+}
+
+pub fn index_entry(
+    track: &Track,
+    output: &Output,
+    path: &Path,
+    loudness: Option<Loudness>,
+) -> IndexEntry {
+    IndexEntry {
+        track_id: track.id.clone(),
+        isrc: track.external_ids.isrc.clone(),
+        album_id: Some(track.album.id.clone()),
+        path: path
+            .strip_prefix(&output.music_home)
+            .unwrap_or(path)
+            .to_path_buf(),
+        loudness,
+    }
+}
+
+/* yt-dlp messages for videos that will never download from here, so
+ * retrying them is pointless */
+const UNAVAILABLE_ERRORS: &[&str] = &[
+    "video unavailable",
+    "private video",
+    "has been removed",
+    "blocked it in your country",
+    "available in your country",
+    "sign in to confirm your age",
+    "members-only",
+];
+
+#[derive(Debug, PartialEq)]
+pub enum DownloadError {
+    /* Removed, private, region locked or age restricted, so another
+     * candidate has to be downloaded instead */
+    Unavailable(String),
+    /* Anything else, such as network errors, which may pass on a retry */
+    Failed(String),
+}
 
-pub fn download_track(track: &Track, url: String) {
-    let music_home = env::var("MUSIC_HOME").expect("MUSIC_HOME environment variable not set");
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::Unavailable(e) => write!(f, "Video unavailable: {}", e),
+            DownloadError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl DownloadError {
+    pub fn from_stderr(stderr: &str) -> DownloadError {
+        let message = stderr.trim().to_string();
+        let lower = message.to_lowercase();
+        if UNAVAILABLE_ERRORS.iter().any(|error| lower.contains(error)) {
+            DownloadError::Unavailable(message)
+        } else {
+            DownloadError::Failed(message)
+        }
+    }
+}
 
-    let path = Path::new(&music_home)
-        .join(&track.album.artists[0].name)
-        .join(&track.album.name);
+/* A track written to disk, and its loudness if it was measured */
+pub struct Downloaded {
+    pub path: PathBuf,
+    pub loudness: Option<Loudness>,
+}
 
-    fs::create_dir_all(&path).expect("Failed to create directories");
+/* Measure the track, and normalize it when asked to. Normalized tracks
+ * need no gain tags, so their loudness isn't kept. */
+async fn process_loudness(track: &Track, path: &Path, output: &Output) -> Option<Loudness> {
+    if output.loudness == LoudnessMode::Off {
+        return None;
+    }
+    let loudness = match loudness::analyze(path, track.duration_ms as f64 / 1000.0).await {
+        Ok(loudness) => loudness,
+        Err(e) => {
+            println!("Failed to measure loudness of {}: {}", track.name, e);
+            return None;
+        }
+    };
+    if output.loudness == LoudnessMode::Normalize {
+        match loudness::normalize(path, output.format, &loudness).await {
+            Ok(()) => println!("Normalized {}", track.name),
+            Err(e) => println!("Failed to normalize {}: {}", track.name, e),
+        }
+        return None;
+    }
+    Some(loudness)
+}
 
-    /* Hash the track name to prevent invalid file names
-     * when the track contains special characters */
-    let mut hasher = Sha256::new();
-    hasher.update(&track.name);
-    let track_name_hash = format!("{:x}", hasher.finalize());
-    let output_path = path.join(format!("{}.mp3", track_name_hash));
+pub async fn download_track(
+    track: &Track,
+    url: String,
+    output: &Output,
+    details: &Details,
+    cover: Option<&Cover>,
+    lyrics: Option<Lyrics>,
+) -> Result<Downloaded, DownloadError> {
+    let writer = writer_for(output.format);
+    let output_path = claim_path(track, output);
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent).map_err(|e| DownloadError::Failed(e.to_string()))?;
+    }
 
     if output_path.exists() {
-        fs::remove_file(&output_path).expect("Failed to delete existing file");
+        fs::remove_file(&output_path).map_err(|e| DownloadError::Failed(e.to_string()))?;
     }
 
-    let output = Command::new("yt-dlp")
+    /* yt-dlp reads the output path as a template of its own, and fills in
+     * the extension once the audio is extracted */
+    let output_template = format!(
+        "{}.%(ext)s",
+        output_path
+            .with_extension("")
+            .to_string_lossy()
+            .replace('%', "%%")
+    );
+    let result = Command::new("yt-dlp")
         .arg("-q")
         .arg("-x")
-        .arg("--audio-quality")
-        .arg("0")
-        .arg("--audio-format")
-        .arg("mp3")
+        .args(format_args(output.format))
         .arg("-o")
-        .arg(output_path.to_str().unwrap())
+        .arg(output_template)
         .arg(url)
         .output()
-        .expect("Failed to execute command");
-
-    if output.status.success() {
-        println!("Downloaded {}", track.name);
-
-        let mut tag = Tag::new();
-        tag.set_album(&track.album.name);
-        tag.set_artist(&track.album.artists[0].name);
-        tag.set_title(&track.name);
-        tag.set_year(
-            track.album.release_date.split('-').collect::<Vec<&str>>()[0]
-                .parse()
-                .unwrap(),
-        );
-        tag.set_track(track.track_number);
-
-        let img_data = reqwest::blocking::get(&track.album.images[0].url)
-            .expect("Failed to download image")
-            .bytes()
-            .expect("Failed to read bytes");
-
-        let picture = frame::Picture {
-            mime_type: "image/jpeg".to_string(),
-            picture_type: PictureType::CoverFront,
-            description: "Cover".to_string(),
-            data: img_data.to_vec(),
-        };
-        let picture_frame = Frame::with_content("APIC", Content::Picture(picture));
-        tag.add_frame(picture_frame);
-
-        match tag.write_to_path(&output_path, Version::Id3v24) {
-            Ok(_) => println!("Tagged {}", output_path.to_str().unwrap()),
-            Err(e) => println!("Failed to tag {}: {}", output_path.to_str().unwrap(), e),
+        .await
+        .map_err(|e| DownloadError::Failed(format!("Failed to run yt-dlp: {}", e)))?;
+
+    if !result.status.success() {
+        return Err(DownloadError::from_stderr(&String::from_utf8_lossy(
+            &result.stderr,
+        )));
+    }
+    println!("Downloaded {}", track.name);
+
+    let loudness = process_loudness(track, &output_path, output).await;
+
+    /* A stale sidecar from an earlier download would outlive the new tags */
+    let sidecar = output_path.with_extension("lrc");
+    match &lyrics {
+        Some(lyrics) => match lyrics.save_sidecar(&output_path) {
+            Ok(path) => println!("Wrote lyrics to {}", path.display()),
+            Err(e) => println!("Failed to write lyrics for {}: {}", track.name, e),
+        },
+        None if sidecar.exists() => {
+            let _ = fs::remove_file(&sidecar);
+        }
+        None => {}
+    }
+
+    let replay_gain = loudness.map(|loudness| ReplayGain {
+        track: Gain::track(&loudness),
+        album: None,
+    });
+    let metadata = Metadata::from_track(track, details, cover.cloned(), lyrics, replay_gain);
+    match writer.write(&output_path, &metadata) {
+        Ok(()) => println!("Tagged {}", output_path.display()),
+        Err(e) => println!("Failed to tag {}: {}", output_path.display(), e),
+    }
+    Ok(Downloaded {
+        path: output_path,
+        loudness,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use id3::TagLike;
+    use serde_json::json;
+
+    use super::*;
+    use crate::migrate::migrate;
+
+    fn track(id: &str) -> Track {
+        serde_json::from_value(json!({
+            "id": id,
+            "name": "Intro",
+            "track_number": 1,
+            "disc_number": 1,
+            "duration_ms": 60000,
+            "album": {
+                "id": "album",
+                "name": "Album",
+                "album_type": "album",
+                "release_date": "2020-01-01",
+                "total_tracks": 1,
+                "images": [],
+                "artists": [{ "id": "artist", "name": "Artist" }]
+            },
+            "artists": [{ "id": "artist", "name": "Artist" }]
+        }))
+        .unwrap()
+    }
+
+    fn output(music_home: &Path) -> Output {
+        Output {
+            music_home: music_home.to_path_buf(),
+            template: PathTemplate::parse("{title}.{ext}").unwrap(),
+            format: OutputFormat::Mp3,
+            loudness: LoudnessMode::Off,
+            claimed: Mutex::new(HashMap::new()),
         }
-    } else {
-        println!("Failed to download {}", track.name);
+    }
+
+    #[test]
+    fn claims_distinct_paths_for_tracks_of_the_same_name() {
+        let music_home = env::temp_dir().join(format!("downloader-claim-{}", process::id()));
+        let output = output(&music_home);
+
+        let first = claim_path(&track("first"), &output);
+        let second = claim_path(&track("second"), &output);
+        assert_eq!(first, music_home.join("Intro.mp3"));
+        assert_eq!(second, music_home.join("Intro (2).mp3"));
+        assert_eq!(claim_path(&track("first"), &output), first);
+    }
+
+    #[test]
+    fn tells_unavailable_videos_from_other_errors() {
+        for stderr in [
+            "ERROR: [youtube] dQw4w9WgXcQ: Video unavailable. This video has been removed by the uploader",
+            "ERROR: [youtube] dQw4w9WgXcQ: The uploader has not made this video available in your country",
+            "ERROR: [youtube] dQw4w9WgXcQ: Private video. Sign in if you've been granted access to this video",
+            "ERROR: [youtube] dQw4w9WgXcQ: Sign in to confirm your age. This video may be inappropriate for some users.",
+        ] {
+            assert!(
+                matches!(DownloadError::from_stderr(stderr), DownloadError::Unavailable(_)),
+                "{}",
+                stderr
+            );
+        }
+        assert!(matches!(
+            DownloadError::from_stderr(
+                "ERROR: [youtube] dQw4w9WgXcQ: Requested format is not available"
+            ),
+            DownloadError::Failed(_)
+        ));
+        assert_eq!(
+            DownloadError::from_stderr("ERROR: unable to download video data: HTTP Error 503\n"),
+            DownloadError::Failed(
+                "ERROR: unable to download video data: HTTP Error 503".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn adopts_untagged_files_at_the_templated_path() {
+        let music_home = env::temp_dir().join(format!("downloader-adopt-{}", process::id()));
+        fs::create_dir_all(&music_home).unwrap();
+        let path = music_home.join("Intro.mp3");
+        fs::copy(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/silence.mp3"),
+            &path,
+        )
+        .unwrap();
+        let output = output(&music_home);
+        let index = LibraryIndex::new(&music_home);
+
+        assert_eq!(
+            existing_download(&track("first"), &output, &index),
+            Some(path.clone())
+        );
+        assert_eq!(
+            writer_for(OutputFormat::Mp3).track_id(&path).as_deref(),
+            Some("first")
+        );
+        assert!(index.contains("first"));
+
+        /* Now that it's tagged, another track of the same name is kept apart */
+        assert_eq!(existing_download(&track("second"), &output, &index), None);
+        assert_eq!(
+            track_path(&track("second"), &output),
+            music_home.join("Intro (2).mp3")
+        );
+
+        fs::remove_dir_all(&music_home).unwrap();
+    }
+
+    #[test]
+    fn finds_migrated_files() {
+        let music_home = env::temp_dir().join(format!("downloader-migrated-{}", process::id()));
+        let hashed = music_home.join(format!("{}.mp3", "a".repeat(64)));
+        fs::create_dir_all(&music_home).unwrap();
+        fs::copy(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/silence.mp3"),
+            &hashed,
+        )
+        .unwrap();
+        let mut tag = id3::Tag::new();
+        tag.set_title("Intro");
+        tag.set_artist("Artist");
+        tag.write_to_path(&hashed, id3::Version::Id3v24).unwrap();
+
+        let output = output(&music_home);
+        migrate(&music_home, &output.template, false).unwrap();
+        let index = LibraryIndex::new(&music_home);
+        assert_eq!(
+            existing_download(&track("first"), &output, &index),
+            Some(music_home.join("Intro.mp3"))
+        );
+        assert!(index.contains("first"));
+
+        fs::remove_dir_all(&music_home).unwrap();
     }
 }
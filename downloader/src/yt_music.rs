@@ -0,0 +1,306 @@
+use std::fmt;
+use std::time::Duration;
+
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+
+/* InnerTube client that the YouTube Music web app identifies as */
+const CLIENT_NAME: &str = "WEB_REMIX";
+const CLIENT_VERSION: &str = "1.20240506.01.00";
+
+/* Search params for the "Songs" filter, as sent by the web app */
+const SONGS_FILTER: &str = "EgWKAQIIAWoMEA4QChADEAQQCRAF";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub video_id: String,
+    pub title: String,
+    pub artists: Vec<String>,
+    pub album: Option<String>,
+    pub duration: Option<Duration>,
+    pub explicit: bool,
+}
+
+impl Candidate {
+    pub fn url(&self) -> String {
+        format!("https://music.youtube.com/watch?v={}", self.video_id)
+    }
+}
+
+#[derive(Debug)]
+pub enum YtMusicError {
+    Request(reqwest::Error),
+    Status(StatusCode),
+    Parse(String),
+}
+
+impl fmt::Display for YtMusicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            YtMusicError::Request(e) => write!(f, "YouTube Music request failed: {}", e),
+            YtMusicError::Status(status) => {
+                write!(f, "Unexpected YouTube Music response: {}", status)
+            }
+            YtMusicError::Parse(message) => {
+                write!(f, "Failed to parse YouTube Music response: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for YtMusicError {}
+
+impl YtMusicError {
+    /* Whether the same search may succeed when tried again */
+    pub fn is_transient(&self) -> bool {
+        match self {
+            YtMusicError::Request(_) => true,
+            YtMusicError::Status(status) => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            YtMusicError::Parse(_) => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for YtMusicError {
+    fn from(e: reqwest::Error) -> Self {
+        YtMusicError::Request(e)
+    }
+}
+
+pub struct YtMusicClient {
+    base_url: String,
+    client: Client,
+}
+
+impl YtMusicClient {
+    pub fn new(base_url: String) -> YtMusicClient {
+        YtMusicClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client: Client::new(),
+        }
+    }
+
+    /* Search songs only, which leaves out music videos and user uploads */
+    pub async fn search(&self, query: &str) -> Result<Vec<Candidate>, YtMusicError> {
+        let body = json!({
+            "context": {
+                "client": {
+                    "clientName": CLIENT_NAME,
+                    "clientVersion": CLIENT_VERSION,
+                    "hl": "en",
+                    "gl": "US",
+                }
+            },
+            "query": query,
+            "params": SONGS_FILTER,
+        });
+
+        let res = self
+            .client
+            .post(format!("{}/youtubei/v1/search?alt=json", self.base_url))
+            .header("Origin", "https://music.youtube.com")
+            .header("Referer", "https://music.youtube.com/")
+            .header(
+                "User-Agent",
+                "Mozilla/5.0 (X11; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0",
+            )
+            .json(&body)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(YtMusicError::Status(res.status()));
+        }
+
+        parse_search(&res.json::<Value>().await?)
+    }
+}
+
+pub fn parse_search(response: &Value) -> Result<Vec<Candidate>, YtMusicError> {
+    let sections = response
+        .pointer("/contents/tabbedSearchResultsRenderer/tabs/0/tabRenderer/content/sectionListRenderer/contents")
+        .and_then(Value::as_array)
+        .ok_or_else(|| YtMusicError::Parse("missing search results".to_string()))?;
+
+    Ok(sections
+        .iter()
+        .filter_map(|section| section.pointer("/musicShelfRenderer/contents"))
+        .filter_map(Value::as_array)
+        .flatten()
+        .filter_map(|item| item.get("musicResponsiveListItemRenderer"))
+        .filter_map(parse_item)
+        .collect())
+}
+
+fn parse_item(item: &Value) -> Option<Candidate> {
+    let video_id = item
+        .pointer("/playlistItemData/videoId")
+        .or_else(|| {
+            item.pointer("/overlay/musicItemThumbnailOverlayRenderer/content/musicPlayButtonRenderer/playNavigationEndpoint/watchEndpoint/videoId")
+        })
+        .and_then(Value::as_str)?
+        .to_string();
+
+    let title = column_runs(item, 0)?
+        .iter()
+        .filter_map(|run| run.get("text").and_then(Value::as_str))
+        .collect::<String>();
+
+    /* The second column reads "Artist & Artist • Album • 3:45", where
+     * artists and albums link to their pages */
+    let mut artists = vec![];
+    let mut album = None;
+    let mut duration = None;
+    for run in column_runs(item, 1).unwrap_or(&vec![]) {
+        let text = match run.get("text").and_then(Value::as_str) {
+            Some(text) => text,
+            None => continue,
+        };
+        match run
+            .pointer("/navigationEndpoint/browseEndpoint/browseEndpointContextSupportedConfigs/browseEndpointContextMusicConfig/pageType")
+            .and_then(Value::as_str)
+        {
+            Some("MUSIC_PAGE_TYPE_ARTIST") | Some("MUSIC_PAGE_TYPE_USER_CHANNEL") => {
+                artists.push(text.to_string())
+            }
+            Some("MUSIC_PAGE_TYPE_ALBUM") => album = Some(text.to_string()),
+            _ => {
+                if let Some(parsed) = parse_duration(text) {
+                    duration = Some(parsed);
+                }
+            }
+        }
+    }
+
+    let explicit = item
+        .get("badges")
+        .and_then(Value::as_array)
+        .map(|badges| {
+            badges.iter().any(|badge| {
+                badge
+                    .pointer("/musicInlineBadgeRenderer/icon/iconType")
+                    .and_then(Value::as_str)
+                    == Some("MUSIC_EXPLICIT_BADGE")
+            })
+        })
+        .unwrap_or(false);
+
+    Some(Candidate {
+        video_id,
+        title,
+        artists,
+        album,
+        duration,
+        explicit,
+    })
+}
+
+fn column_runs(item: &Value, column: usize) -> Option<&Vec<Value>> {
+    item.get("flexColumns")?
+        .get(column)?
+        .pointer("/musicResponsiveListItemFlexColumnRenderer/text/runs")?
+        .as_array()
+}
+
+/* Parse "3:45" or "1:02:03" */
+fn parse_duration(text: &str) -> Option<Duration> {
+    let parts = text
+        .trim()
+        .split(':')
+        .map(|part| part.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if parts.len() < 2 || parts.len() > 3 {
+        return None;
+    }
+    let seconds = parts.iter().fold(0, |total, part| total * 60 + part);
+    Some(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEARCH_FIXTURE: &str = include_str!("../tests/fixtures/yt_music_search.json");
+
+    #[test]
+    fn parses_song_results() {
+        let response: Value = serde_json::from_str(SEARCH_FIXTURE).unwrap();
+        let candidates = parse_search(&response).unwrap();
+
+        assert_eq!(candidates.len(), 3);
+        assert_eq!(
+            candidates[0],
+            Candidate {
+                video_id: "fHiGbolFFGw".to_string(),
+                title: "Paranoid Android".to_string(),
+                artists: vec!["Radiohead".to_string()],
+                album: Some("OK Computer".to_string()),
+                duration: Some(Duration::from_secs(387)),
+                explicit: false,
+            }
+        );
+        assert_eq!(
+            candidates[1].artists,
+            vec!["Christopher O'Riley".to_string(), "Radiohead".to_string()]
+        );
+        assert!(candidates[2].explicit);
+        assert_eq!(candidates[2].album, None);
+    }
+
+    #[test]
+    fn rejects_unexpected_response() {
+        assert!(parse_search(&json!({ "error": { "code": 400 } })).is_err());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("3:45"), Some(Duration::from_secs(225)));
+        assert_eq!(parse_duration("1:02:03"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_duration("1.2M plays"), None);
+        assert_eq!(parse_duration("2024"), None);
+    }
+
+    #[tokio::test]
+    async fn searches_mock_server() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/youtubei/v1/search")
+            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::PartialJson(json!({
+                "query": "Paranoid Android Radiohead",
+                "params": SONGS_FILTER,
+            })))
+            .with_header("content-type", "application/json")
+            .with_body(SEARCH_FIXTURE)
+            .create_async()
+            .await;
+
+        let client = YtMusicClient::new(server.url());
+        let candidates = client.search("Paranoid Android Radiohead").await.unwrap();
+
+        mock.assert_async().await;
+        assert_eq!(candidates.len(), 3);
+        assert_eq!(
+            candidates[0].url(),
+            "https://music.youtube.com/watch?v=fHiGbolFFGw"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_error_status() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/youtubei/v1/search")
+            .match_query(mockito::Matcher::Any)
+            .with_status(403)
+            .create_async()
+            .await;
+
+        let client = YtMusicClient::new(server.url());
+        match client.search("anything").await {
+            Err(YtMusicError::Status(status)) => assert_eq!(status, StatusCode::FORBIDDEN),
+            other => panic!("Expected a status error, got {:?}", other),
+        }
+    }
+}
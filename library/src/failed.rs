@@ -0,0 +1,76 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::index::write_atomic;
+use crate::paths::sanitize;
+
+/* Next to the library index, where the API can read them once the job that
+ * wrote them has ended */
+const FAILED_DIR: &str = ".library/failed";
+
+fn failed_path(music_home: &Path, job_name: &str) -> PathBuf {
+    music_home
+        .join(FAILED_DIR)
+        .join(format!("{}.json", sanitize(job_name)))
+}
+
+/* The tracks of a job that weren't downloaded */
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FailedTracks {
+    /* Given up on after retrying, so worth another job */
+    pub failed: Vec<String>,
+    /* Nothing scored above the threshold, which another job won't change */
+    #[serde(default)]
+    pub no_match: Vec<String>,
+}
+
+impl FailedTracks {
+    pub fn is_empty(&self) -> bool {
+        self.failed.is_empty() && self.no_match.is_empty()
+    }
+}
+
+/* Record the tracks a job didn't download, so only the failed ones are
+ * requeued */
+pub fn save_failed(music_home: &Path, job_name: &str, tracks: &FailedTracks) -> io::Result<()> {
+    write_atomic(
+        &failed_path(music_home, job_name),
+        &serde_json::to_vec(tracks)?,
+    )
+}
+
+/* The tracks the job didn't download, or None when it listed none. The
+ * list is removed once read. */
+pub fn take_failed(music_home: &Path, job_name: &str) -> Option<FailedTracks> {
+    let path = failed_path(music_home, job_name);
+    let tracks = serde_json::from_slice(&fs::read(&path).ok()?).ok();
+    let _ = fs::remove_file(&path);
+    tracks
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn saves_and_takes_failed_tracks() {
+        let dir = env::temp_dir().join(format!("library-failed-{}", process::id()));
+        let tracks = FailedTracks {
+            failed: vec!["4uLU6hMCjMI75M1A2tKUQC".to_string()],
+            no_match: vec!["6rqhFgbbKwnb9MLmUQDhG6".to_string()],
+        };
+
+        assert_eq!(take_failed(&dir, "downloader-1"), None);
+        save_failed(&dir, "downloader-1", &tracks).unwrap();
+        assert_eq!(take_failed(&dir, "downloader-1"), Some(tracks));
+        assert_eq!(take_failed(&dir, "downloader-1"), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+
+/* Audio formats the downloader can write */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Opus,
+    M4a,
+    Mp3,
+    Flac,
+}
+
+#[derive(Debug)]
+pub struct FormatError(String);
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unknown output format {:?}, expected opus, m4a, aac, mp3 or flac",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+impl FromStr for OutputFormat {
+    type Err = FormatError;
+
+    fn from_str(format: &str) -> Result<OutputFormat, FormatError> {
+        match format.trim().to_lowercase().as_str() {
+            "opus" => Ok(OutputFormat::Opus),
+            /* AAC is written in an MP4 container, which is what players expect */
+            "m4a" | "aac" => Ok(OutputFormat::M4a),
+            "mp3" => Ok(OutputFormat::Mp3),
+            "flac" => Ok(OutputFormat::Flac),
+            _ => Err(FormatError(format.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+impl OutputFormat {
+    /* The format from OUTPUT_FORMAT, or MP3 */
+    pub fn from_env() -> Result<OutputFormat, FormatError> {
+        env::var("OUTPUT_FORMAT")
+            .map(|format| format.parse())
+            .unwrap_or(Ok(OutputFormat::Mp3))
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Opus => "opus",
+            OutputFormat::M4a => "m4a",
+            OutputFormat::Mp3 => "mp3",
+            OutputFormat::Flac => "flac",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_formats() {
+        assert_eq!("opus".parse::<OutputFormat>().unwrap(), OutputFormat::Opus);
+        assert_eq!("AAC".parse::<OutputFormat>().unwrap(), OutputFormat::M4a);
+        assert_eq!(" m4a ".parse::<OutputFormat>().unwrap(), OutputFormat::M4a);
+        assert_eq!("flac".parse::<OutputFormat>().unwrap().extension(), "flac");
+        assert!("wav".parse::<OutputFormat>().is_err());
+    }
+}
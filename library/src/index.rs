@@ -0,0 +1,196 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::paths::sanitize;
+
+/* Kept on the music volume so the API and every downloader share it */
+const INDEX_DIR: &str = ".library";
+
+/* Numbers the temporary files of `write_atomic`, so threads writing the
+ * same path don't share one */
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/* A track that has been downloaded, with its path relative to MUSIC_HOME */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub track_id: String,
+    #[serde(default)]
+    pub isrc: Option<String>,
+    #[serde(default)]
+    pub album_id: Option<String>,
+    pub path: PathBuf,
+    #[serde(default)]
+    pub loudness: Option<Loudness>,
+}
+
+/* Measured when the track was downloaded, so album gain can be worked out
+ * once the rest of the album has been downloaded by other jobs */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Loudness {
+    /* Integrated loudness in LUFS */
+    pub integrated: f64,
+    /* True peak as a linear amplitude, where 1.0 is full scale */
+    pub peak: f64,
+    /* Length in seconds */
+    pub duration: f64,
+}
+
+/* Downloaded tracks keyed by Spotify track id and by ISRC. Every key is a
+ * file of its own that is replaced atomically, so jobs running at the same
+ * time never overwrite each other's entries. */
+#[derive(Debug, Clone)]
+pub struct LibraryIndex {
+    music_home: PathBuf,
+}
+
+impl LibraryIndex {
+    pub fn new(music_home: &Path) -> LibraryIndex {
+        LibraryIndex {
+            music_home: music_home.to_path_buf(),
+        }
+    }
+
+    /* The entry for the track, or for another release of the same
+     * recording. Entries whose file has since been removed don't count. */
+    pub fn find(&self, track_id: &str, isrc: Option<&str>) -> Option<IndexEntry> {
+        self.read(&self.key_path("tracks", track_id)).or_else(|| {
+            isrc.and_then(|isrc| self.read(&self.key_path("isrc", &isrc.to_uppercase())))
+        })
+    }
+
+    pub fn contains(&self, track_id: &str) -> bool {
+        self.find(track_id, None).is_some()
+    }
+
+    pub fn insert(&self, entry: &IndexEntry) -> io::Result<()> {
+        let data = serde_json::to_vec(entry)?;
+        write_atomic(&self.key_path("tracks", &entry.track_id), &data)?;
+        if let Some(isrc) = &entry.isrc {
+            write_atomic(&self.key_path("isrc", &isrc.to_uppercase()), &data)?;
+        }
+        if let Some(album_id) = &entry.album_id {
+            write_atomic(
+                &self
+                    .album_dir(album_id)
+                    .join(format!("{}.json", sanitize(&entry.track_id))),
+                &data,
+            )?;
+        }
+        Ok(())
+    }
+
+    /* Every track of the album that is in the library */
+    pub fn album(&self, album_id: &str) -> Vec<IndexEntry> {
+        let mut entries: Vec<IndexEntry> = fs::read_dir(self.album_dir(album_id))
+            .into_iter()
+            .flatten()
+            .filter_map(|file| file.ok())
+            .filter(|file| file.path().extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|file| self.read(&file.path()))
+            .collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        entries
+    }
+
+    pub fn full_path(&self, entry: &IndexEntry) -> PathBuf {
+        self.music_home.join(&entry.path)
+    }
+
+    fn key_path(&self, kind: &str, key: &str) -> PathBuf {
+        self.music_home
+            .join(INDEX_DIR)
+            .join(kind)
+            .join(format!("{}.json", sanitize(key)))
+    }
+
+    fn album_dir(&self, album_id: &str) -> PathBuf {
+        self.music_home
+            .join(INDEX_DIR)
+            .join("albums")
+            .join(sanitize(album_id))
+    }
+
+    fn read(&self, path: &Path) -> Option<IndexEntry> {
+        let entry: IndexEntry = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+        self.full_path(&entry).is_file().then_some(entry)
+    }
+}
+
+/* Write `data` to a temporary file next to `path` and move it into place,
+ * so readers never see a partly written file */
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp = path.with_extension(format!(
+        "{}-{}.tmp",
+        process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&temp, data)?;
+    fs::rename(&temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn finds_entries_by_track_id_or_isrc() {
+        let dir = env::temp_dir().join(format!("library-index-{}", process::id()));
+        fs::create_dir_all(dir.join("Artist")).unwrap();
+        fs::write(dir.join("Artist/Song.mp3"), "").unwrap();
+
+        let index = LibraryIndex::new(&dir);
+        let entry = IndexEntry {
+            track_id: "4uLU6hMCjMI75M1A2tKUQC".to_string(),
+            isrc: Some("GBARL9300135".to_string()),
+            album_id: Some("2noRn2Aes5aoNVsU6iWThc".to_string()),
+            path: PathBuf::from("Artist/Song.mp3"),
+            loudness: None,
+        };
+        assert!(!index.contains(&entry.track_id));
+        index.insert(&entry).unwrap();
+
+        assert_eq!(index.find(&entry.track_id, None), Some(entry.clone()));
+        assert_eq!(
+            index.find("6rqhFgbbKwnb9MLmUQDhG6", Some("gbarl9300135")),
+            Some(entry.clone())
+        );
+        assert_eq!(index.find("6rqhFgbbKwnb9MLmUQDhG6", None), None);
+        assert_eq!(index.album("2noRn2Aes5aoNVsU6iWThc"), vec![entry.clone()]);
+
+        /* Deleting the file takes the track out of the library */
+        fs::remove_file(dir.join("Artist/Song.mp3")).unwrap();
+        assert!(!index.contains(&entry.track_id));
+        assert!(index.album("2noRn2Aes5aoNVsU6iWThc").is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn writes_the_same_path_from_many_threads() {
+        let dir = env::temp_dir().join(format!("library-atomic-{}", process::id()));
+        let path = dir.join("entry.json");
+
+        thread::scope(|scope| {
+            for n in 0..8 {
+                let path = &path;
+                scope.spawn(move || write_atomic(path, n.to_string().as_bytes()).unwrap());
+            }
+        });
+        let written: usize = fs::read_to_string(&path).unwrap().parse().unwrap();
+        assert!(written < 8);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,19 @@
+mod failed;
+mod format;
+mod index;
+mod notifier;
+mod paths;
+mod subsonic;
+
+pub use crate::failed::{save_failed, take_failed, FailedTracks};
+pub use crate::format::{FormatError, OutputFormat};
+pub use crate::index::{write_atomic, IndexEntry, LibraryIndex, Loudness};
+pub use crate::notifier::{
+    notifiers_from, notifiers_from_env, Jellyfin, Notifier, NotifyError, Plex, Webhook,
+    NOTIFIER_ENV,
+};
+pub use crate::paths::{
+    sanitize, sanitize_file_name, unique_path, unique_path_by, PathFields, PathTemplate,
+    TemplateError, DEFAULT_TEMPLATE,
+};
+pub use crate::subsonic::{token, ScanStatus, SubsonicClient, SubsonicError};
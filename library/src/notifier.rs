@@ -0,0 +1,436 @@
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde_json::json;
+
+use crate::subsonic::{SubsonicClient, SubsonicError};
+
+/* Everything the notifiers are configured with, so the API can pass it on
+ * to downloaders as it is */
+pub const NOTIFIER_ENV: &[&str] = &[
+    "NOTIFIERS",
+    "SUBSONIC_URL",
+    "SUBSONIC_PORT",
+    "SUBSONIC_USERNAME",
+    "SUBSONIC_PASSWORD",
+    "JELLYFIN_URL",
+    "JELLYFIN_API_KEY",
+    "JELLYFIN_MUSIC_PATH",
+    "PLEX_URL",
+    "PLEX_TOKEN",
+    "PLEX_SECTION_ID",
+    "PLEX_MUSIC_PATH",
+    "WEBHOOK_URL",
+];
+
+#[derive(Debug)]
+pub enum NotifyError {
+    Request(reqwest::Error),
+    Status(StatusCode),
+    Subsonic(SubsonicError),
+}
+
+impl fmt::Display for NotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotifyError::Request(e) => write!(f, "Request failed: {}", e),
+            NotifyError::Status(status) => write!(f, "Unexpected response: {}", status),
+            NotifyError::Subsonic(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for NotifyError {}
+
+impl From<reqwest::Error> for NotifyError {
+    fn from(e: reqwest::Error) -> Self {
+        NotifyError::Request(e)
+    }
+}
+
+impl From<SubsonicError> for NotifyError {
+    fn from(e: SubsonicError) -> Self {
+        NotifyError::Subsonic(e)
+    }
+}
+
+/* Tells a media server that the library changed so it picks up new files */
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /* `dirs` are the directories with new files, relative to MUSIC_HOME.
+     * Servers that can't refresh part of the library rescan all of it. */
+    async fn notify(&self, dirs: &[PathBuf]) -> Result<(), NotifyError>;
+}
+
+/* The notifiers named by NOTIFIERS */
+pub fn notifiers_from_env() -> Result<Vec<Box<dyn Notifier>>, String> {
+    notifiers_from(&|name| env::var(name).ok().filter(|value| !value.is_empty()))
+}
+
+/* A comma separated list of `subsonic` (or `navidrome`), `jellyfin`, `plex`
+ * and `webhook`. Unset, a configured Subsonic server is still scanned as it
+ * was before notifiers could be picked. */
+pub fn notifiers_from(
+    var: &dyn Fn(&str) -> Option<String>,
+) -> Result<Vec<Box<dyn Notifier>>, String> {
+    let names = match var("NOTIFIERS") {
+        Some(names) => names.to_lowercase(),
+        None if var("SUBSONIC_URL").is_some() => "subsonic".to_string(),
+        None => return Ok(vec![]),
+    };
+    names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty() && *name != "none")
+        .map(|name| notifier(name, var))
+        .collect()
+}
+
+fn notifier(name: &str, var: &dyn Fn(&str) -> Option<String>) -> Result<Box<dyn Notifier>, String> {
+    let required = |name: &str| var(name).ok_or_else(|| format!("{} is not set", name));
+    match name {
+        "subsonic" | "navidrome" => Ok(Box::new(SubsonicClient::from_vars(var)?)),
+        "jellyfin" => Ok(Box::new(Jellyfin::new(
+            required("JELLYFIN_URL")?,
+            required("JELLYFIN_API_KEY")?,
+            var("JELLYFIN_MUSIC_PATH"),
+        ))),
+        "plex" => Ok(Box::new(Plex::new(
+            required("PLEX_URL")?,
+            required("PLEX_TOKEN")?,
+            required("PLEX_SECTION_ID")?,
+            var("PLEX_MUSIC_PATH"),
+        ))),
+        "webhook" => Ok(Box::new(Webhook::new(required("WEBHOOK_URL")?))),
+        other => Err(format!("Unknown notifier: {}", other)),
+    }
+}
+
+/* Where the server sees a directory, given where it mounts MUSIC_HOME */
+fn server_path(music_path: &str, dir: &Path) -> String {
+    format!(
+        "{}/{}",
+        music_path.trim_end_matches('/'),
+        dir.to_string_lossy()
+    )
+}
+
+async fn send(request: RequestBuilder) -> Result<(), NotifyError> {
+    let res = request.send().await?;
+    if !res.status().is_success() {
+        return Err(NotifyError::Status(res.status()));
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Notifier for SubsonicClient {
+    fn name(&self) -> &'static str {
+        "subsonic"
+    }
+
+    /* Subsonic servers can only scan everything, so wait for it to finish
+     * rather than start overlapping scans */
+    async fn notify(&self, _dirs: &[PathBuf]) -> Result<(), NotifyError> {
+        self.scan().await?;
+        Ok(())
+    }
+}
+
+pub struct Jellyfin {
+    base_url: String,
+    api_key: String,
+    /* Where Jellyfin mounts MUSIC_HOME. Without it the whole library is
+     * refreshed. */
+    music_path: Option<String>,
+    client: Client,
+}
+
+impl Jellyfin {
+    pub fn new(base_url: String, api_key: String, music_path: Option<String>) -> Jellyfin {
+        Jellyfin {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            music_path,
+            client: Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for Jellyfin {
+    fn name(&self) -> &'static str {
+        "jellyfin"
+    }
+
+    async fn notify(&self, dirs: &[PathBuf]) -> Result<(), NotifyError> {
+        let request = match &self.music_path {
+            Some(music_path) if !dirs.is_empty() => {
+                let updates: Vec<_> = dirs
+                    .iter()
+                    .map(|dir| {
+                        json!({ "Path": server_path(music_path, dir), "UpdateType": "Modified" })
+                    })
+                    .collect();
+                self.client
+                    .post(format!("{}/Library/Media/Updated", self.base_url))
+                    .json(&json!({ "Updates": updates }))
+            }
+            _ => self
+                .client
+                .post(format!("{}/Library/Refresh", self.base_url)),
+        };
+        send(request.header(
+            "Authorization",
+            format!("MediaBrowser Token=\"{}\"", self.api_key),
+        ))
+        .await
+    }
+}
+
+pub struct Plex {
+    base_url: String,
+    token: String,
+    section_id: String,
+    /* Where Plex mounts MUSIC_HOME. Without it the whole section is
+     * refreshed. */
+    music_path: Option<String>,
+    client: Client,
+}
+
+impl Plex {
+    pub fn new(
+        base_url: String,
+        token: String,
+        section_id: String,
+        music_path: Option<String>,
+    ) -> Plex {
+        Plex {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+            section_id,
+            music_path,
+            client: Client::new(),
+        }
+    }
+
+    fn refresh(&self) -> RequestBuilder {
+        self.client
+            .get(format!(
+                "{}/library/sections/{}/refresh",
+                self.base_url, self.section_id
+            ))
+            .header("X-Plex-Token", &self.token)
+    }
+}
+
+#[async_trait]
+impl Notifier for Plex {
+    fn name(&self) -> &'static str {
+        "plex"
+    }
+
+    /* Plex refreshes one path per request */
+    async fn notify(&self, dirs: &[PathBuf]) -> Result<(), NotifyError> {
+        match &self.music_path {
+            Some(music_path) if !dirs.is_empty() => {
+                for dir in dirs {
+                    send(
+                        self.refresh()
+                            .query(&[("path", server_path(music_path, dir))]),
+                    )
+                    .await?;
+                }
+                Ok(())
+            }
+            _ => send(self.refresh()).await,
+        }
+    }
+}
+
+/* POSTs the changed directories as JSON, for anything else that wants to
+ * know */
+pub struct Webhook {
+    url: String,
+    client: Client,
+}
+
+impl Webhook {
+    pub fn new(url: String) -> Webhook {
+        Webhook {
+            url,
+            client: Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for Webhook {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    async fn notify(&self, dirs: &[PathBuf]) -> Result<(), NotifyError> {
+        let paths: Vec<_> = dirs.iter().map(|dir| dir.to_string_lossy()).collect();
+        send(
+            self.client
+                .post(&self.url)
+                .json(&json!({ "event": "library.updated", "paths": paths })),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use mockito::Matcher;
+
+    use super::*;
+
+    fn names(vars: &[(&str, &str)]) -> Result<Vec<&'static str>, String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let notifiers = notifiers_from(&|name| vars.get(name).cloned())?;
+        Ok(notifiers.iter().map(|notifier| notifier.name()).collect())
+    }
+
+    fn dirs() -> Vec<PathBuf> {
+        vec![
+            PathBuf::from("Pink Floyd/The Wall"),
+            PathBuf::from("Pink Floyd/Animals"),
+        ]
+    }
+
+    #[test]
+    fn picks_notifiers_from_config() {
+        let subsonic = [
+            ("SUBSONIC_URL", "http://navidrome"),
+            ("SUBSONIC_USERNAME", "admin"),
+            ("SUBSONIC_PASSWORD", "sesame"),
+        ];
+        assert_eq!(names(&[]), Ok(vec![]));
+        assert_eq!(names(&subsonic), Ok(vec!["subsonic"]));
+        assert_eq!(
+            names(&[
+                ("NOTIFIERS", "Jellyfin, webhook"),
+                ("JELLYFIN_URL", "http://jellyfin:8096"),
+                ("JELLYFIN_API_KEY", "key"),
+                ("WEBHOOK_URL", "http://hooks/music"),
+            ]),
+            Ok(vec!["jellyfin", "webhook"])
+        );
+        assert_eq!(names(&[("NOTIFIERS", "none"), subsonic[0]]), Ok(vec![]));
+
+        assert_eq!(
+            names(&[("NOTIFIERS", "plex"), ("PLEX_URL", "http://plex:32400")]),
+            Err("PLEX_TOKEN is not set".to_string())
+        );
+        assert_eq!(
+            names(&[("NOTIFIERS", "emby")]),
+            Err("Unknown notifier: emby".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn refreshes_jellyfin_paths_or_library() {
+        let mut server = mockito::Server::new_async().await;
+        let updated = server
+            .mock("POST", "/Library/Media/Updated")
+            .match_header("authorization", "MediaBrowser Token=\"key\"")
+            .match_body(Matcher::Json(json!({
+                "Updates": [
+                    { "Path": "/media/music/Pink Floyd/The Wall", "UpdateType": "Modified" },
+                    { "Path": "/media/music/Pink Floyd/Animals", "UpdateType": "Modified" }
+                ]
+            })))
+            .with_status(204)
+            .create_async()
+            .await;
+        let refresh = server
+            .mock("POST", "/Library/Refresh")
+            .with_status(204)
+            .create_async()
+            .await;
+
+        Jellyfin::new(server.url(), "key".into(), Some("/media/music/".into()))
+            .notify(&dirs())
+            .await
+            .unwrap();
+        Jellyfin::new(server.url(), "key".into(), None)
+            .notify(&dirs())
+            .await
+            .unwrap();
+
+        updated.assert_async().await;
+        refresh.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn refreshes_plex_section_by_path() {
+        let mut server = mockito::Server::new_async().await;
+        let mut mock = |path: &'static str| {
+            server
+                .mock("GET", "/library/sections/3/refresh")
+                .match_header("x-plex-token", "token")
+                .match_query(Matcher::UrlEncoded("path".into(), path.into()))
+                .create()
+        };
+        let wall = mock("/data/music/Pink Floyd/The Wall");
+        let animals = mock("/data/music/Pink Floyd/Animals");
+
+        Plex::new(
+            server.url(),
+            "token".into(),
+            "3".into(),
+            Some("/data/music".into()),
+        )
+        .notify(&dirs())
+        .await
+        .unwrap();
+
+        wall.assert_async().await;
+        animals.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn posts_changed_dirs_to_webhook() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/hook")
+            .match_body(Matcher::Json(json!({
+                "event": "library.updated",
+                "paths": ["Pink Floyd/The Wall", "Pink Floyd/Animals"]
+            })))
+            .create_async()
+            .await;
+        let failing = server
+            .mock("POST", "/broken")
+            .with_status(500)
+            .create_async()
+            .await;
+
+        Webhook::new(format!("{}/hook", server.url()))
+            .notify(&dirs())
+            .await
+            .unwrap();
+        match Webhook::new(format!("{}/broken", server.url()))
+            .notify(&dirs())
+            .await
+        {
+            Err(NotifyError::Status(status)) => assert_eq!(status, 500),
+            other => panic!("Expected a status error, got {:?}", other),
+        }
+
+        mock.assert_async().await;
+        failing.assert_async().await;
+    }
+}
@@ -0,0 +1,385 @@
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use unicode_normalization::UnicodeNormalization;
+
+use spotify::Track;
+
+pub const DEFAULT_TEMPLATE: &str =
+    "{album_artist}/{album} ({year})/{disc}-{track:02} {title}.{ext}";
+
+/* Most filesystems allow 255 bytes per name. Leave room for collision
+ * suffixes and the temporary extensions yt-dlp appends while downloading. */
+const MAX_COMPONENT_BYTES: usize = 200;
+
+/* Characters that are invalid on at least one common filesystem */
+const RESERVED_CHARS: [char; 9] = ['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/* Values a path template can refer to */
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathFields {
+    pub album_artist: String,
+    pub artist: String,
+    pub artists: String,
+    pub album: String,
+    pub title: String,
+    pub year: String,
+    pub disc: u32,
+    pub track: u32,
+    pub ext: String,
+}
+
+impl PathFields {
+    pub fn from_track(track: &Track, ext: &str) -> PathFields {
+        let artists: Vec<&str> = track
+            .artists
+            .iter()
+            .map(|artist| artist.name.as_str())
+            .collect();
+        PathFields {
+            album_artist: track
+                .album
+                .artists
+                .first()
+                .or(track.artists.first())
+                .map(|artist| artist.name.clone())
+                .unwrap_or_default(),
+            artist: artists.first().copied().unwrap_or_default().to_string(),
+            artists: artists.join(", "),
+            album: track.album.name.clone(),
+            title: track.name.clone(),
+            year: track
+                .album
+                .release_date
+                .split('-')
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            disc: track.disc_number,
+            track: track.track_number,
+            ext: ext.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    AlbumArtist,
+    Artist,
+    Artists,
+    Album,
+    Title,
+    Year,
+    Disc,
+    Track,
+    Ext,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Field> {
+        match name {
+            "album_artist" => Some(Field::AlbumArtist),
+            "artist" => Some(Field::Artist),
+            "artists" => Some(Field::Artists),
+            "album" => Some(Field::Album),
+            "title" => Some(Field::Title),
+            "year" => Some(Field::Year),
+            "disc" => Some(Field::Disc),
+            "track" => Some(Field::Track),
+            "ext" => Some(Field::Ext),
+            _ => None,
+        }
+    }
+
+    fn value(&self, fields: &PathFields, width: usize) -> String {
+        match self {
+            Field::AlbumArtist => fields.album_artist.clone(),
+            Field::Artist => fields.artist.clone(),
+            Field::Artists => fields.artists.clone(),
+            Field::Album => fields.album.clone(),
+            Field::Title => fields.title.clone(),
+            Field::Year => fields.year.clone(),
+            Field::Disc => format!("{:0width$}", fields.disc, width = width),
+            Field::Track => format!("{:0width$}", fields.track, width = width),
+            Field::Ext => fields.ext.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Field(Field, usize),
+}
+
+#[derive(Debug)]
+pub struct TemplateError(String);
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid path template: {}", self.0)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/* A layout such as "{album_artist}/{album}/{track:02} {title}.{ext}", where
+ * each `/` separates a directory and `{track:02}` zero pads to two digits */
+#[derive(Debug, Clone)]
+pub struct PathTemplate {
+    components: Vec<Vec<Part>>,
+}
+
+impl PathTemplate {
+    pub fn parse(template: &str) -> Result<PathTemplate, TemplateError> {
+        let components = template
+            .split('/')
+            .filter(|component| !component.is_empty())
+            .map(parse_component)
+            .collect::<Result<Vec<_>, _>>()?;
+        if components.is_empty() {
+            return Err(TemplateError("template is empty".to_string()));
+        }
+        Ok(PathTemplate { components })
+    }
+
+    /* The template from PATH_TEMPLATE, or the default one */
+    pub fn from_env() -> Result<PathTemplate, TemplateError> {
+        PathTemplate::parse(
+            &env::var("PATH_TEMPLATE").unwrap_or_else(|_| DEFAULT_TEMPLATE.to_string()),
+        )
+    }
+
+    /* The path relative to MUSIC_HOME, with every component made safe to
+     * write. Values can't add directories, since slashes in them are
+     * replaced along with other reserved characters. */
+    pub fn render(&self, fields: &PathFields) -> PathBuf {
+        let last = self.components.len() - 1;
+        self.components
+            .iter()
+            .enumerate()
+            .map(|(i, parts)| {
+                let component: String = parts
+                    .iter()
+                    .map(|part| match part {
+                        Part::Literal(text) => text.clone(),
+                        Part::Field(field, width) => field.value(fields, *width),
+                    })
+                    .collect();
+                if i == last {
+                    sanitize_file_name(&component)
+                } else {
+                    sanitize(&component)
+                }
+            })
+            .collect()
+    }
+}
+
+fn parse_component(component: &str) -> Result<Vec<Part>, TemplateError> {
+    let mut parts = vec![];
+    let mut rest = component;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            parts.push(Part::Literal(rest[..start].to_string()));
+        }
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| TemplateError(format!("unclosed field in {:?}", component)))?;
+
+        let spec = &rest[start + 1..end];
+        let (name, width) = match spec.split_once(':') {
+            Some((name, width)) => (
+                name,
+                width
+                    .parse()
+                    .map_err(|_| TemplateError(format!("invalid width in {{{}}}", spec)))?,
+            ),
+            None => (spec, 0),
+        };
+        let field = Field::parse(name)
+            .ok_or_else(|| TemplateError(format!("unknown field {{{}}}", name)))?;
+        parts.push(Part::Field(field, width));
+
+        rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Literal(rest.to_string()));
+    }
+    Ok(parts)
+}
+
+/* Make a single path component safe on any common filesystem */
+pub fn sanitize(component: &str) -> String {
+    let cleaned: String = component
+        .nfc()
+        .map(|c| {
+            if c.is_control() || RESERVED_CHARS.contains(&c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    /* Leading dots hide files, and Windows drops trailing dots and spaces */
+    let trimmed = cleaned
+        .trim()
+        .trim_start_matches('.')
+        .trim_end_matches(['.', ' '])
+        .to_string();
+    let mut name = truncate(&trimmed, MAX_COMPONENT_BYTES)
+        .trim_end()
+        .to_string();
+
+    let stem = name.split('.').next().unwrap_or_default().to_uppercase();
+    if name.is_empty() || RESERVED_NAMES.contains(&stem.as_str()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+/* Like `sanitize`, but keeps the extension when truncating */
+pub fn sanitize_file_name(file_name: &str) -> String {
+    match file_name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && ext.len() <= 5 => {
+            let ext = sanitize(ext);
+            let stem = truncate(&sanitize(stem), MAX_COMPONENT_BYTES - ext.len() - 1)
+                .trim_end_matches(['.', ' '])
+                .to_string();
+            format!("{}.{}", stem, ext)
+        }
+        _ => sanitize(file_name),
+    }
+}
+
+fn truncate(text: &str, max_bytes: usize) -> &str {
+    if text.len() <= max_bytes {
+        return text;
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/* `path`, or the first of "name (2).ext", "name (3).ext", ... that is either
+ * free or already holds the same track according to `is_same` */
+pub fn unique_path(path: &Path, is_same: impl Fn(&Path) -> bool) -> PathBuf {
+    unique_path_by(path, |path| !path.exists() || is_same(path))
+}
+
+/* Like `unique_path`, for callers that know of paths taken by files that
+ * don't exist yet */
+pub fn unique_path_by(path: &Path, is_free: impl Fn(&Path) -> bool) -> PathBuf {
+    if is_free(path) {
+        return path.to_path_buf();
+    }
+
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    (2..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, ext)))
+        .find(|candidate| is_free(candidate))
+        .expect("Ran out of file names")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn fields() -> PathFields {
+        PathFields {
+            album_artist: "Radiohead".to_string(),
+            artist: "Radiohead".to_string(),
+            artists: "Radiohead".to_string(),
+            album: "OK Computer".to_string(),
+            title: "Paranoid Android".to_string(),
+            year: "1997".to_string(),
+            disc: 1,
+            track: 2,
+            ext: "mp3".to_string(),
+        }
+    }
+
+    #[test]
+    fn renders_default_template() {
+        let template = PathTemplate::parse(DEFAULT_TEMPLATE).unwrap();
+        assert_eq!(
+            template.render(&fields()),
+            Path::new("Radiohead/OK Computer (1997)/1-02 Paranoid Android.mp3")
+        );
+    }
+
+    #[test]
+    fn keeps_slashes_in_values_inside_their_component() {
+        let template = PathTemplate::parse("{artists}/{title}.{ext}").unwrap();
+        let mut fields = fields();
+        fields.artists = "AC/DC".to_string();
+        fields.title = "What's Next? <Live>".to_string();
+        assert_eq!(
+            template.render(&fields),
+            Path::new("AC_DC/What's Next_ _Live_.mp3")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert!(PathTemplate::parse("{genre}/{title}.{ext}").is_err());
+        assert!(PathTemplate::parse("{album/{title}").is_err());
+        assert!(PathTemplate::parse("{track:two}").is_err());
+        assert!(PathTemplate::parse("/").is_err());
+    }
+
+    #[test]
+    fn sanitizes_components() {
+        assert_eq!(sanitize("..hidden"), "hidden");
+        assert_eq!(sanitize("Trailing. "), "Trailing");
+        assert_eq!(sanitize("  "), "_");
+        assert_eq!(sanitize("CON"), "_CON");
+        assert_eq!(sanitize("nul.mp3"), "_nul.mp3");
+        assert_eq!(sanitize("Tab\there"), "Tab_here");
+        /* Decomposed "é" is composed so names compare equal across systems */
+        assert_eq!(sanitize("Beyonce\u{301}"), "Beyonc\u{e9}");
+    }
+
+    #[test]
+    fn truncates_long_names_and_keeps_extension() {
+        let name = sanitize_file_name(&format!("{}.opus", "é".repeat(300)));
+        assert!(name.len() <= MAX_COMPONENT_BYTES);
+        assert!(name.ends_with("é.opus"));
+        assert!(sanitize(&"a".repeat(300)).len() <= MAX_COMPONENT_BYTES);
+    }
+
+    #[test]
+    fn finds_free_path_on_collision() {
+        let dir = env::temp_dir().join(format!("library-paths-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("Song.mp3");
+        fs::write(&path, "other").unwrap();
+        fs::write(dir.join("Song (2).mp3"), "other").unwrap();
+
+        let is_same = |path: &Path| fs::read_to_string(path).unwrap() == "same";
+        assert_eq!(unique_path(&path, is_same), dir.join("Song (3).mp3"));
+
+        fs::write(dir.join("Song (2).mp3"), "same").unwrap();
+        assert_eq!(unique_path(&path, is_same), dir.join("Song (2).mp3"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,270 @@
+use std::fmt;
+use std::time::Duration;
+
+use rand::distributions::{Alphanumeric, DistString};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use tokio::time::{sleep, Instant};
+
+/* Token authentication needs API version 1.13.0 or later */
+const API_VERSION: &str = "1.15.0";
+const CLIENT_NAME: &str = "distributed-streaming";
+
+const SCAN_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const SCAN_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Debug)]
+pub enum SubsonicError {
+    Request(reqwest::Error),
+    Status(StatusCode),
+    /* An error the server reported in its response, such as code 40 for a
+     * wrong username or password */
+    Api { code: u32, message: String },
+    Timeout,
+}
+
+impl fmt::Display for SubsonicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubsonicError::Request(e) => write!(f, "Subsonic request failed: {}", e),
+            SubsonicError::Status(status) => write!(f, "Unexpected Subsonic response: {}", status),
+            SubsonicError::Api { code, message } => {
+                write!(f, "Subsonic error {}: {}", code, message)
+            }
+            SubsonicError::Timeout => write!(f, "Timed out waiting for the Subsonic scan"),
+        }
+    }
+}
+
+impl std::error::Error for SubsonicError {}
+
+impl From<reqwest::Error> for SubsonicError {
+    fn from(e: reqwest::Error) -> Self {
+        SubsonicError::Request(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ScanStatus {
+    pub scanning: bool,
+    #[serde(default)]
+    pub count: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(rename = "subsonic-response")]
+    response: Response,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+    status: String,
+    error: Option<ApiError>,
+    scan_status: Option<ScanStatus>,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    code: u32,
+    #[serde(default)]
+    message: String,
+}
+
+/* The salted token the password is sent as, so it never appears in URLs */
+pub fn token(password: &str, salt: &str) -> String {
+    format!("{:x}", md5::compute(format!("{}{}", password, salt)))
+}
+
+pub struct SubsonicClient {
+    base_url: String,
+    username: String,
+    password: String,
+    client: Client,
+}
+
+impl SubsonicClient {
+    pub fn new(base_url: String, username: String, password: String) -> SubsonicClient {
+        SubsonicClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            username,
+            password,
+            client: Client::new(),
+        }
+    }
+
+    /* Configured from SUBSONIC_URL, SUBSONIC_PORT, SUBSONIC_USERNAME and
+     * SUBSONIC_PASSWORD, as looked up by `var` */
+    pub fn from_vars(var: &dyn Fn(&str) -> Option<String>) -> Result<SubsonicClient, String> {
+        let required = |name: &str| var(name).ok_or_else(|| format!("{} is not set", name));
+        let url = required("SUBSONIC_URL")?;
+        let url = match var("SUBSONIC_PORT") {
+            Some(port) => format!("{}:{}", url.trim_end_matches('/'), port),
+            None => url,
+        };
+        Ok(SubsonicClient::new(
+            url,
+            required("SUBSONIC_USERNAME")?,
+            required("SUBSONIC_PASSWORD")?,
+        ))
+    }
+
+    async fn request(&self, endpoint: &str) -> Result<Response, SubsonicError> {
+        let salt = Alphanumeric.sample_string(&mut rand::thread_rng(), 12);
+        let res = self
+            .client
+            .get(format!("{}/rest/{}", self.base_url, endpoint))
+            .query(&[
+                ("u", self.username.as_str()),
+                ("t", token(&self.password, &salt).as_str()),
+                ("s", salt.as_str()),
+                ("v", API_VERSION),
+                ("c", CLIENT_NAME),
+                ("f", "json"),
+            ])
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(SubsonicError::Status(res.status()));
+        }
+
+        let response = res.json::<Envelope>().await?.response;
+        if response.status != "ok" {
+            let error = response.error.unwrap_or(ApiError {
+                code: 0,
+                message: format!("status {}", response.status),
+            });
+            return Err(SubsonicError::Api {
+                code: error.code,
+                message: error.message,
+            });
+        }
+        Ok(response)
+    }
+
+    async fn scan_request(&self, endpoint: &str) -> Result<ScanStatus, SubsonicError> {
+        self.request(endpoint)
+            .await?
+            .scan_status
+            .ok_or_else(|| SubsonicError::Api {
+                code: 0,
+                message: "response has no scan status".to_string(),
+            })
+    }
+
+    pub async fn start_scan(&self) -> Result<ScanStatus, SubsonicError> {
+        self.scan_request("startScan").await
+    }
+
+    pub async fn scan_status(&self) -> Result<ScanStatus, SubsonicError> {
+        self.scan_request("getScanStatus").await
+    }
+
+    /* Start a scan and wait until the server has finished it */
+    pub async fn scan(&self) -> Result<ScanStatus, SubsonicError> {
+        self.scan_with(SCAN_POLL_INTERVAL, SCAN_TIMEOUT).await
+    }
+
+    async fn scan_with(
+        &self,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Result<ScanStatus, SubsonicError> {
+        let deadline = Instant::now() + timeout;
+        let mut status = self.start_scan().await?;
+        while status.scanning {
+            if Instant::now() >= deadline {
+                return Err(SubsonicError::Timeout);
+            }
+            sleep(interval).await;
+            status = self.scan_status().await?;
+        }
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher;
+    use serde_json::json;
+
+    use super::*;
+
+    fn response(body: serde_json::Value) -> String {
+        json!({ "subsonic-response": body }).to_string()
+    }
+
+    #[test]
+    fn computes_token() {
+        /* The example from the Subsonic API documentation */
+        assert_eq!(
+            token("sesame", "c19b2d"),
+            "26719a1196d2a940705a59634eb18eab"
+        );
+    }
+
+    #[tokio::test]
+    async fn scans_until_done() {
+        let mut server = mockito::Server::new_async().await;
+        let start = server
+            .mock("GET", "/rest/startScan")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("u".into(), "admin".into()),
+                Matcher::UrlEncoded("f".into(), "json".into()),
+                Matcher::Regex("t=[0-9a-f]{32}".into()),
+                Matcher::Regex("s=[0-9A-Za-z]{12}".into()),
+            ]))
+            .with_body(response(json!({
+                "status": "ok",
+                "scanStatus": { "scanning": true, "count": 10 }
+            })))
+            .create_async()
+            .await;
+        let status = server
+            .mock("GET", "/rest/getScanStatus")
+            .match_query(Matcher::Any)
+            .with_body(response(json!({
+                "status": "ok",
+                "scanStatus": { "scanning": false, "count": 25 }
+            })))
+            .create_async()
+            .await;
+
+        let client = SubsonicClient::new(server.url(), "admin".into(), "sesame".into());
+        let result = client
+            .scan_with(Duration::from_millis(10), Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        start.assert_async().await;
+        status.assert_async().await;
+        assert_eq!(
+            result,
+            ScanStatus {
+                scanning: false,
+                count: Some(25)
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_api_errors() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/rest/startScan")
+            .match_query(Matcher::Any)
+            .with_body(response(json!({
+                "status": "failed",
+                "error": { "code": 40, "message": "Wrong username or password" }
+            })))
+            .create_async()
+            .await;
+
+        let client = SubsonicClient::new(server.url(), "admin".into(), "wrong".into());
+        match client.scan().await {
+            Err(SubsonicError::Api { code, .. }) => assert_eq!(code, 40),
+            other => panic!("Expected an API error, got {:?}", other),
+        }
+    }
+}
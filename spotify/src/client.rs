@@ -0,0 +1,200 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use reqwest::{header::RETRY_AFTER, Client, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_value, Value};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+
+/* Refresh the access token this long before Spotify expires it */
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/* Attempts per request for rate limited and server errors */
+const MAX_ATTEMPTS: u32 = 4;
+
+/* Longer rate limits are returned to the caller rather than waited out */
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum SpotifyError {
+    /* The credentials were rejected, or the token can't access the resource */
+    Auth(String),
+    NotFound(String),
+    /* Still rate limited after every attempt, or asked to wait longer than
+     * MAX_RETRY_AFTER, with the last Retry-After */
+    RateLimited(Duration),
+    Server(StatusCode),
+    Status(StatusCode),
+    Request(reqwest::Error),
+    Parse(serde_json::Error),
+}
+
+impl fmt::Display for SpotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpotifyError::Auth(message) => write!(f, "Spotify authentication failed: {}", message),
+            SpotifyError::NotFound(uri) => write!(f, "Spotify resource not found: {}", uri),
+            SpotifyError::RateLimited(retry_after) => write!(
+                f,
+                "Spotify rate limit exceeded, retry after {}s",
+                retry_after.as_secs()
+            ),
+            SpotifyError::Server(status) => write!(f, "Spotify server error: {}", status),
+            SpotifyError::Status(status) => write!(f, "Unexpected Spotify response: {}", status),
+            SpotifyError::Request(e) => write!(f, "Spotify request failed: {}", e),
+            SpotifyError::Parse(e) => write!(f, "Failed to parse Spotify response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SpotifyError {}
+
+impl From<reqwest::Error> for SpotifyError {
+    fn from(e: reqwest::Error) -> Self {
+        SpotifyError::Request(e)
+    }
+}
+
+impl From<serde_json::Error> for SpotifyError {
+    fn from(e: serde_json::Error) -> Self {
+        SpotifyError::Parse(e)
+    }
+}
+
+fn retry_after(res: &Response) -> Duration {
+    res.headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(1))
+}
+
+pub struct SpotifyClient {
+    client_id: String,
+    secret: String,
+    client: Client,
+    token: Mutex<Option<AccessToken>>,
+}
+
+struct AccessToken {
+    value: String,
+    expires_at: Instant,
+}
+
+#[derive(Serialize)]
+struct AuthRequest<'a> {
+    grant_type: &'a str,
+    client_id: &'a str,
+    client_secret: &'a str,
+}
+
+#[derive(Deserialize)]
+struct AuthResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+impl SpotifyClient {
+    pub fn new(client_id: String, secret: String) -> SpotifyClient {
+        SpotifyClient {
+            client_id,
+            secret,
+            client: Client::new(),
+            token: Mutex::new(None),
+        }
+    }
+
+    /* Client credentials tokens last an hour, so one is reused until it is
+     * about to expire */
+    async fn get_access_token(&self) -> Result<String, SpotifyError> {
+        let mut token = self.token.lock().await;
+        if let Some(token) = token.as_ref() {
+            if Instant::now() + TOKEN_EXPIRY_MARGIN < token.expires_at {
+                return Ok(token.value.clone());
+            }
+        }
+
+        let req = AuthRequest {
+            grant_type: "client_credentials",
+            client_id: &self.client_id,
+            client_secret: &self.secret,
+        };
+        let res = self
+            .client
+            .post("https://accounts.spotify.com/api/token")
+            .form(&req)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(SpotifyError::Auth(res.text().await.unwrap_or_default()));
+        }
+        let res = res.json::<AuthResponse>().await?;
+
+        *token = Some(AccessToken {
+            value: res.access_token.clone(),
+            expires_at: Instant::now() + Duration::from_secs(res.expires_in),
+        });
+        Ok(res.access_token)
+    }
+
+    pub async fn api_req(&self, uri: &str) -> Result<Value, SpotifyError> {
+        let url = format!("https://api.spotify.com/v1{}", uri);
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let token = self.get_access_token().await?;
+            let res = self
+                .client
+                .get(&url)
+                .header("Content-Type", "application/json")
+                .bearer_auth(token)
+                .send()
+                .await?;
+
+            let status = res.status();
+            let retry_in = match status {
+                status if status.is_success() => return Ok(res.json::<Value>().await?),
+                StatusCode::UNAUTHORIZED => {
+                    /* The cached token may have been revoked early */
+                    *self.token.lock().await = None;
+                    if attempt > 1 {
+                        return Err(SpotifyError::Auth(res.text().await.unwrap_or_default()));
+                    }
+                    Duration::ZERO
+                }
+                StatusCode::FORBIDDEN => {
+                    return Err(SpotifyError::Auth(res.text().await.unwrap_or_default()))
+                }
+                StatusCode::NOT_FOUND => return Err(SpotifyError::NotFound(uri.to_string())),
+                StatusCode::TOO_MANY_REQUESTS => {
+                    let retry_in = retry_after(&res);
+                    if attempt >= MAX_ATTEMPTS || retry_in > MAX_RETRY_AFTER {
+                        return Err(SpotifyError::RateLimited(retry_in));
+                    }
+                    retry_in
+                }
+                status if status.is_server_error() => {
+                    if attempt >= MAX_ATTEMPTS {
+                        return Err(SpotifyError::Server(status));
+                    }
+                    Duration::from_millis(500 * 2u64.pow(attempt - 1))
+                }
+                status => return Err(SpotifyError::Status(status)),
+            };
+
+            println!(
+                "Spotify returned {} for {}, retrying in {:?}",
+                status, uri, retry_in
+            );
+            sleep(retry_in).await;
+        }
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self, uri: &str) -> Result<T, SpotifyError> {
+        Ok(from_value::<T>(self.api_req(uri).await?)?)
+    }
+}
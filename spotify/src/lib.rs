@@ -0,0 +1,8 @@
+mod client;
+mod models;
+
+pub use crate::client::{SpotifyClient, SpotifyError};
+pub use crate::models::{
+    Album, AlbumTrack, Albums, Artist, Artists, Copyright, ExternalIds, Image, Items, Playlist,
+    PlaylistItem, PlaylistOwner, SpotifySearchResponse, Track, Tracks,
+};
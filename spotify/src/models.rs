@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SpotifySearchResponse {
+    pub tracks: Option<Items<Track>>,
+    pub albums: Option<Items<Album>>,
+    pub artists: Option<Items<Artist>>,
+    /* Spotify returns null entries among playlist search results */
+    pub playlists: Option<Items<Option<Playlist>>>,
+}
+
+/* A page of results; `next` is the URL of the following page, if any */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Items<T> {
+    pub items: Vec<T>,
+    pub next: Option<String>,
+    pub total: u32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Tracks {
+    pub tracks: Vec<Track>,
+}
+
+/* Several albums or artists fetched by id, with null entries for unknown ids */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Albums {
+    pub albums: Vec<Option<Album>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Artists {
+    pub artists: Vec<Option<Artist>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Track {
+    pub id: String,
+    pub name: String,
+    pub track_number: u32,
+    pub disc_number: u32,
+    pub duration_ms: u64,
+    #[serde(default)]
+    pub explicit: bool,
+    #[serde(default)]
+    pub external_ids: ExternalIds,
+    pub album: Album,
+    pub artists: Vec<Artist>,
+}
+
+/* A track listed on an album, without the album itself */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AlbumTrack {
+    pub id: String,
+    pub name: String,
+    pub track_number: u32,
+    pub disc_number: u32,
+    pub duration_ms: u64,
+    pub artists: Vec<Artist>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ExternalIds {
+    pub isrc: Option<String>,
+    pub ean: Option<String>,
+    pub upc: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Album {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub album_type: String,
+    pub release_date: String,
+    #[serde(default)]
+    pub release_date_precision: String,
+    #[serde(default)]
+    pub total_tracks: u32,
+    pub artists: Vec<Artist>,
+    #[serde(default)]
+    pub images: Vec<Image>,
+    /* Only the full album object has these */
+    #[serde(default)]
+    pub genres: Vec<String>,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub copyrights: Vec<Copyright>,
+    #[serde(default)]
+    pub tracks: Option<Items<AlbumTrack>>,
+}
+
+/* `type` is "C" for the copyright and "P" for the sound recording copyright */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Copyright {
+    pub text: String,
+    #[serde(rename = "type")]
+    pub copyright_type: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Artist {
+    pub id: String,
+    pub name: String,
+    /* Only the full artist object has genres */
+    #[serde(default)]
+    pub genres: Vec<String>,
+}
+
+/* Spotify leaves the dimensions out for some user uploaded images */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Image {
+    pub url: String,
+    pub height: Option<u32>,
+    pub width: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Playlist {
+    pub id: String,
+    pub name: String,
+    pub owner: PlaylistOwner,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PlaylistOwner {
+    pub display_name: Option<String>,
+}
+
+/* Playlist entries can be local files or podcast episodes, which don't
+ * deserialize as a `Track`, so the track is kept as raw JSON */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PlaylistItem {
+    #[serde(default)]
+    pub is_local: bool,
+    pub track: Option<Value>,
+}
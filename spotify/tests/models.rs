@@ -0,0 +1,109 @@
+use serde_json::from_str;
+
+use spotify::{
+    Album, AlbumTrack, Albums, Artists, Items, PlaylistItem, SpotifySearchResponse, Track,
+};
+
+#[test]
+fn parses_track() {
+    let track: Track = from_str(include_str!("fixtures/track.json")).unwrap();
+
+    assert_eq!(track.id, "6LgJvl0Xdtc73RJ1mmpotq");
+    assert_eq!(track.name, "Paranoid Android");
+    assert_eq!(track.track_number, 2);
+    assert_eq!(track.disc_number, 1);
+    assert_eq!(track.duration_ms, 383493);
+    assert!(!track.explicit);
+    assert_eq!(track.external_ids.isrc.as_deref(), Some("GBAYE9700100"));
+    assert_eq!(track.artists[0].name, "Radiohead");
+
+    assert_eq!(track.album.name, "OK Computer");
+    assert_eq!(track.album.release_date, "1997-05-28");
+    assert_eq!(track.album.release_date_precision, "day");
+    assert_eq!(track.album.total_tracks, 12);
+    assert_eq!(track.album.images.len(), 3);
+    assert_eq!(track.album.images[0].width, Some(640));
+    assert!(track.album.tracks.is_none());
+    assert!(track.artists[0].genres.is_empty());
+}
+
+#[test]
+fn parses_album_tracks_page() {
+    let page: Items<AlbumTrack> = from_str(include_str!("fixtures/album_tracks.json")).unwrap();
+
+    assert_eq!(page.total, 12);
+    assert!(page.next.is_some());
+    assert_eq!(page.items.len(), 2);
+    assert_eq!(page.items[0].name, "Airbag");
+    assert_eq!(page.items[1].track_number, 2);
+}
+
+#[test]
+fn parses_last_artist_albums_page() {
+    let page: Items<Album> = from_str(include_str!("fixtures/artist_albums.json")).unwrap();
+
+    assert_eq!(page.total, 51);
+    assert!(page.next.is_none());
+    assert_eq!(page.items[0].name, "Pablo Honey");
+    assert_eq!(page.items[0].release_date_precision, "year");
+}
+
+#[test]
+fn parses_playlist_items_with_local_and_missing_tracks() {
+    let page: Items<PlaylistItem> =
+        from_str(include_str!("fixtures/playlist_tracks.json")).unwrap();
+    assert_eq!(page.items.len(), 3);
+
+    let track: Track = serde_json::from_value(page.items[0].track.clone().unwrap()).unwrap();
+    assert_eq!(track.name, "Paranoid Android");
+    assert_eq!(track.album.images[0].height, None);
+
+    assert!(page.items[1].is_local);
+    assert!(serde_json::from_value::<Track>(page.items[1].track.clone().unwrap()).is_err());
+
+    assert!(page.items[2].track.is_none());
+}
+
+#[test]
+fn parses_search_with_null_playlists() {
+    let search: SpotifySearchResponse = from_str(include_str!("fixtures/search.json")).unwrap();
+
+    let tracks = search.tracks.unwrap();
+    assert_eq!(tracks.total, 812);
+    assert_eq!(tracks.items[0].album.name, "OK Computer");
+
+    assert!(search.albums.unwrap().items[0].images.is_empty());
+    assert_eq!(search.artists.unwrap().items[0].name, "Radiohead");
+
+    let playlists = search.playlists.unwrap().items;
+    assert!(playlists[0].is_none());
+    let playlist = playlists[1].as_ref().unwrap();
+    assert_eq!(playlist.name, "Radiohead Essentials");
+    assert_eq!(playlist.owner.display_name, None);
+}
+
+#[test]
+fn parses_full_albums() {
+    let albums: Albums = from_str(include_str!("fixtures/albums.json")).unwrap();
+    assert!(albums.albums[1].is_none());
+
+    let album = albums.albums[0].as_ref().unwrap();
+    assert_eq!(album.label.as_deref(), Some("XL Recordings"));
+    assert!(album.genres.is_empty());
+    assert_eq!(album.copyrights[1].copyright_type, "P");
+
+    let tracks = album.tracks.as_ref().unwrap();
+    assert_eq!(tracks.total, 12);
+    assert!(tracks.next.is_some());
+    assert_eq!(tracks.items[1].name, "Paranoid Android");
+}
+
+#[test]
+fn parses_full_artists() {
+    let artists: Artists = from_str(include_str!("fixtures/artists.json")).unwrap();
+    assert!(artists.artists[1].is_none());
+
+    let artist = artists.artists[0].as_ref().unwrap();
+    assert_eq!(artist.name, "Radiohead");
+    assert_eq!(artist.genres.len(), 5);
+}